@@ -0,0 +1,139 @@
+//! Cost of refreshing a single staking pool on a mainnet-sized index: rebuilding the whole
+//! delegator -> staking pools direction (as every refresh used to) against applying the
+//! difference of that staking pool only.
+//!
+//! Run with `cargo bench --bench index`.
+
+// Its tests are not built with the benchmark.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/index.rs"]
+mod index;
+// Only the interning itself is used here.
+#[allow(dead_code)]
+#[path = "../src/interner.rs"]
+mod interner;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use interner::{Id, Interner};
+
+/// Roughly the number of staking pools deployed on mainnet.
+const STAKING_POOLS: usize = 400;
+/// Accounts the delegators are drawn from, so that many of them delegate to several pools.
+const ACCOUNTS: usize = 500_000;
+/// Delegators of the largest staking pool, the others get fewer following Zipf's law.
+const LARGEST_POOL: usize = 120_000;
+const SMALLEST_POOL: usize = 20;
+/// Share of the delegators of a staking pool that join or leave between two refreshes.
+const CHURN: f64 = 0.01;
+
+/// Visibility does not matter here, positions are plain flags.
+type Position = bool;
+
+fn account(rng: &mut StdRng, interner: &Interner) -> Id {
+    let n = rng.gen_range(0..ACCOUNTS);
+    // About a third of the delegators are implicit accounts.
+    if n % 3 == 0 {
+        interner.intern(&format!(
+            "{:064x}",
+            (n as u128).wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c835)
+        ))
+    } else {
+        interner.intern(&format!("delegator-{n}.near"))
+    }
+}
+
+fn staking_pool(rank: usize) -> Id {
+    Id::from(format!("pool-{rank}.poolv1.near"))
+}
+
+fn fixture(rng: &mut StdRng, interner: &Interner) -> index::ByStakingPool<Position> {
+    (0..STAKING_POOLS)
+        .map(|rank| {
+            let size = (LARGEST_POOL / (rank + 1)).max(SMALLEST_POOL);
+            let delegators = (0..size).map(|_| (account(rng, interner), true)).collect();
+            (staking_pool(rank), Arc::new(delegators))
+        })
+        .collect()
+}
+
+/// The positions of `delegators` after a refresh: some delegators left and others joined.
+fn churned(
+    rng: &mut StdRng,
+    interner: &Interner,
+    delegators: &BTreeMap<Id, Position>,
+) -> BTreeMap<Id, Position> {
+    let changes = ((delegators.len() as f64 * CHURN) as usize).max(1);
+    let mut accounts: Vec<_> = delegators.keys().cloned().collect();
+    accounts.shuffle(rng);
+    accounts.truncate(accounts.len() - changes.min(accounts.len()));
+
+    let mut result: BTreeMap<_, _> = accounts
+        .into_iter()
+        .map(|account| (account, true))
+        .collect();
+    for _ in 0..changes {
+        result.insert(account(rng, interner), true);
+    }
+    result
+}
+
+fn bench_refresh(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let interner = Interner::default();
+    let mut by_staking_pool = fixture(&mut rng, &interner);
+    let mut by_delegator = index::build_by_delegator(&by_staking_pool, |_, visible| *visible);
+
+    let positions: usize = by_staking_pool
+        .values()
+        .map(|delegators| delegators.len())
+        .sum();
+    println!(
+        "Fixture: {} staking pools, {} delegators, {} positions",
+        by_staking_pool.len(),
+        by_delegator.len(),
+        positions
+    );
+
+    for (name, rank) in [("largest", 0), ("median", STAKING_POOLS / 2)] {
+        let staking_pool = staking_pool(rank);
+        let current = churned(&mut rng, &interner, &by_staking_pool[&staking_pool]);
+
+        let mut group = c.benchmark_group(format!("refresh_{name}_pool"));
+        group.sample_size(10);
+
+        group.bench_function("full_rebuild", |b| {
+            b.iter(|| {
+                black_box(index::build_by_delegator(&by_staking_pool, |_, visible| {
+                    *visible
+                }))
+            });
+        });
+
+        // Goes back and forth between the two sets of delegators, so that every iteration
+        // applies a refresh with the same amount of changes.
+        let mut next = Arc::new(current);
+        group.bench_function("incremental", |b| {
+            b.iter(|| {
+                let (added, removed) = index::diff(
+                    by_staking_pool.get(&staking_pool).map(Arc::as_ref),
+                    &next,
+                    |visible| *visible,
+                    |visible| *visible,
+                );
+                index::apply_diff(&mut by_delegator, &staking_pool, &added, &removed);
+                next = by_staking_pool
+                    .insert(staking_pool.clone(), std::mem::take(&mut next))
+                    .unwrap_or_default();
+            });
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_refresh);
+criterion_main!(benches);
@@ -0,0 +1,308 @@
+use crate::config::AuthConfig;
+use crate::network::AppState;
+
+use hmac::{Hmac, Mac};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Hex-encoded HMAC-SHA256 of `<timestamp>.<body>`, optionally prefixed with `sha256=`.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Unix timestamp (seconds) the signature was made at.
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Why a request was refused, kept in the request's local cache for the 401 catcher.
+#[derive(Debug, Clone, Default)]
+pub struct AuthFailure(pub String);
+
+/// Checks the credentials of the routes that change the index, and remembers the signatures
+/// it accepted so that a captured request cannot be sent again.
+pub struct Authenticator {
+    config: AuthConfig,
+    /// Accepted signatures with their timestamp, until they are too old to be accepted anyway.
+    seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            config,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    const fn is_open(&self) -> bool {
+        self.config.token.is_none() && self.config.webhook_secret.is_none()
+    }
+
+    fn tolerance(&self) -> i64 {
+        i64::try_from(self.config.signature_tolerance).unwrap_or(i64::MAX)
+    }
+
+    fn check_token(&self, authorization: &str) -> bool {
+        let (Some(token), Some(given)) =
+            (&self.config.token, authorization.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        // Compared in constant time, so that the token cannot be guessed byte by byte.
+        token.len() == given.len()
+            && token
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    fn check_signature_headers(
+        &self,
+        signature: &str,
+        timestamp: Option<&str>,
+    ) -> Result<(i64, Vec<u8>), String> {
+        let timestamp = timestamp
+            .and_then(|timestamp| timestamp.parse::<i64>().ok())
+            .ok_or_else(|| format!("Missing or invalid {TIMESTAMP_HEADER} header"))?;
+        if (chrono::Utc::now().timestamp() - timestamp).abs() > self.tolerance() {
+            return Err(format!(
+                "{TIMESTAMP_HEADER} is too far from the current time"
+            ));
+        }
+
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature))
+            .map_err(|_| format!("{SIGNATURE_HEADER} is not hex-encoded"))?;
+
+        Ok((timestamp, signature))
+    }
+}
+
+/// Request guard of the routes that change the index.
+///
+/// A bearer token is checked right away. A signature can only be checked against the body,
+/// so a route accepting signed requests has to call [`Authorized::verify_body`] before acting
+/// on it.
+pub enum Authorized<'r> {
+    /// No credentials are configured.
+    Open,
+    Token,
+    Signed {
+        authenticator: &'r Authenticator,
+        timestamp: i64,
+        signature: Vec<u8>,
+    },
+}
+
+impl Authorized<'_> {
+    pub fn verify_body(&self, body: &[u8]) -> Result<(), String> {
+        let Self::Signed {
+            authenticator,
+            timestamp,
+            signature,
+        } = self
+        else {
+            return Ok(());
+        };
+        let Some(secret) = &authenticator.config.webhook_secret else {
+            return Err("Signed requests are not accepted".to_string());
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .map_err(|_| "Invalid webhook secret".to_string())?;
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        mac.verify_slice(signature)
+            .map_err(|_| "Signature does not match the body".to_string())?;
+
+        let now = chrono::Utc::now().timestamp();
+        let tolerance = authenticator.tolerance();
+        let mut seen = authenticator
+            .seen
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        seen.retain(|_, seen_timestamp| now - *seen_timestamp <= tolerance);
+        if seen.insert(signature.clone(), *timestamp).is_some() {
+            return Err("Request was already received".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(app_state) = request.rocket().state::<AppState>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let authenticator = &app_state.authenticator;
+
+        if authenticator.is_open() {
+            return Outcome::Success(Authorized::Open);
+        }
+
+        let headers = request.headers();
+        if let Some(authorization) = headers.get_one("Authorization") {
+            if authenticator.check_token(authorization) {
+                return Outcome::Success(Authorized::Token);
+            }
+        }
+
+        let failure = match headers.get_one(SIGNATURE_HEADER) {
+            Some(signature) if authenticator.config.webhook_secret.is_some() => match authenticator
+                .check_signature_headers(signature, headers.get_one(TIMESTAMP_HEADER))
+            {
+                Ok((timestamp, signature)) => {
+                    return Outcome::Success(Authorized::Signed {
+                        authenticator,
+                        timestamp,
+                        signature,
+                    });
+                }
+                Err(failure) => failure,
+            },
+            _ => "Missing or invalid credentials".to_string(),
+        };
+
+        warn!("Rejected unauthenticated request: {failure}");
+        request.local_cache(|| AuthFailure(failure));
+        Outcome::Error((Status::Unauthorized, ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "webhook secret";
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(AuthConfig {
+            token: Some("token".to_string()),
+            webhook_secret: Some(SECRET.to_string()),
+            signature_tolerance: 300,
+        })
+    }
+
+    fn sign(timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Checks the headers of a request signed at `timestamp` and then its `body`.
+    fn verify(
+        authenticator: &Authenticator,
+        signature: &str,
+        timestamp: i64,
+        body: &[u8],
+    ) -> Result<(), String> {
+        let (timestamp, signature) =
+            authenticator.check_signature_headers(signature, Some(&timestamp.to_string()))?;
+
+        Authorized::Signed {
+            authenticator,
+            timestamp,
+            signature,
+        }
+        .verify_body(body)
+    }
+
+    #[test]
+    fn bearer_tokens_have_to_match() {
+        let authenticator = authenticator();
+
+        assert!(authenticator.check_token("Bearer token"));
+        assert!(!authenticator.check_token("Bearer toke"));
+        assert!(!authenticator.check_token("Bearer tokens"));
+        assert!(!authenticator.check_token("token"));
+        assert!(!Authenticator::new(AuthConfig {
+            token: None,
+            ..authenticator.config.clone()
+        })
+        .check_token("Bearer "));
+    }
+
+    #[test]
+    fn signatures_have_to_match_the_body() {
+        let authenticator = authenticator();
+        let now = chrono::Utc::now().timestamp();
+        let body = br#"{"receiver_id":"a.poolv1.near","block_height":101}"#;
+
+        assert_eq!(
+            verify(&authenticator, &sign(now, b"{}"), now, body),
+            Err("Signature does not match the body".to_string())
+        );
+        // The timestamp is part of what is signed.
+        assert_eq!(
+            verify(&authenticator, &sign(now - 1, body), now, body),
+            Err("Signature does not match the body".to_string())
+        );
+        assert!(verify(&authenticator, "sha256=zz", now, body)
+            .unwrap_err()
+            .ends_with("is not hex-encoded"));
+
+        // The `sha256=` prefix is optional.
+        let signature = sign(now, body);
+        assert_eq!(
+            verify(
+                &authenticator,
+                signature.trim_start_matches("sha256="),
+                now,
+                body
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn signed_requests_are_only_accepted_once() {
+        let authenticator = authenticator();
+        let now = chrono::Utc::now().timestamp();
+        let body = b"{}";
+
+        assert_eq!(verify(&authenticator, &sign(now, body), now, body), Ok(()));
+        assert_eq!(
+            verify(&authenticator, &sign(now, body), now, body),
+            Err("Request was already received".to_string())
+        );
+        // The same body sent again later is a new request.
+        assert_eq!(
+            verify(&authenticator, &sign(now - 1, body), now - 1, body),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn timestamps_too_far_from_the_clock_are_refused() {
+        let authenticator = authenticator();
+        let now = chrono::Utc::now().timestamp();
+        let body = b"{}";
+
+        for timestamp in [now - 301, now + 301] {
+            assert!(
+                verify(&authenticator, &sign(timestamp, body), timestamp, body)
+                    .unwrap_err()
+                    .ends_with("is too far from the current time")
+            );
+        }
+        for timestamp in [now - 299, now + 299] {
+            assert_eq!(
+                verify(&authenticator, &sign(timestamp, body), timestamp, body),
+                Ok(())
+            );
+        }
+        assert!(authenticator
+            .check_signature_headers(&sign(now, body), None)
+            .unwrap_err()
+            .starts_with("Missing or invalid"));
+        assert!(authenticator
+            .check_signature_headers(&sign(now, body), Some("yesterday"))
+            .unwrap_err()
+            .starts_with("Missing or invalid"));
+    }
+}
@@ -0,0 +1,604 @@
+use color_eyre::{eyre::Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Delegators that joined or left a staking pool between two refreshes of it.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ChangeEvent {
+    /// Position in the log, usable as the `since` cursor.
+    pub id: u64,
+    pub validator_account_id: String,
+    /// Block height the new delegators were read at.
+    pub block_height: u64,
+    pub timestamp: i64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Set on the event recorded in place of events that were dropped because the index was
+    /// loaded from a cache older than them. Whoever applied the dropped events has to
+    /// download the whole index again.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub reset: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ChangesPage {
+    pub events: Vec<ChangeEvent>,
+    /// Cursor to pass as `since` to get the following events.
+    pub next: u64,
+}
+
+/// A single delegator joining or leaving a staking pool.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum MutationKind {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Mutation {
+    /// Version of the index this mutation leads to.
+    pub version: u64,
+    pub op: MutationKind,
+    pub account_id: String,
+    pub staking_pool: String,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncPage {
+    pub mutations: Vec<Mutation>,
+    /// Version reached after applying `mutations`, to pass as `since_version` next time.
+    pub version: u64,
+    /// Whether there are newer mutations than the ones returned.
+    pub more: bool,
+}
+
+/// Change of the delegators of a staking pool, before it is recorded as a [`ChangeEvent`].
+pub struct Change {
+    pub validator_account_id: String,
+    pub block_height: u64,
+    pub timestamp: i64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+struct ChangeLogInner {
+    file: tokio::fs::File,
+    events: Vec<ChangeEvent>,
+    /// Id of the latest event known to be on disk. Newer events are not served yet.
+    synced: u64,
+}
+
+impl ChangeLogInner {
+    /// Events known to be on disk.
+    fn synced_events(&self) -> &[ChangeEvent] {
+        &self.events[..self.events.partition_point(|event| event.id <= self.synced)]
+    }
+}
+
+/// Append-only NDJSON log of [`ChangeEvent`]s, also kept in memory to serve queries.
+///
+/// The id of the latest event is the version of the index: every change of which delegators
+/// are in which staking pool gets a new one. Only the latest `retention` events are kept.
+///
+/// Appending only writes the events, so that their versions can be handed out under the
+/// locks of the index. They are served once [`ChangeLog::sync`] made them durable, which can
+/// be done once for many appends.
+pub struct ChangeLog {
+    path: PathBuf,
+    retention: usize,
+    inner: Mutex<ChangeLogInner>,
+}
+
+impl ChangeLog {
+    /// Opens the log of an index loaded at `index_version`. Events newer than that version
+    /// never made it into the loaded index, e.g. after a crash before the cache was flushed,
+    /// so they are dropped and replaced with a reset event. So are missing events the index
+    /// was written after.
+    pub async fn open(path: PathBuf, retention: usize, index_version: Option<u64>) -> Result<Self> {
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read change log <{}>", path.display()))
+            }
+        };
+
+        let mut events = Vec::new();
+        for line in content.lines() {
+            match serde_json::from_str::<ChangeEvent>(line) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!(
+                    "Skipping unreadable change event in <{}>: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        let version = version_of(&events);
+        // A reset leaves the index as it is, so one recorded after the index was written does
+        // not call for another.
+        let dropped = index_version.is_some_and(|index_version| {
+            events
+                .iter()
+                .any(|event| event.id > index_version && !event.reset)
+        });
+        // Events the index was written after but that never made it to the log, e.g. when the
+        // log was deleted, so that their versions would otherwise be handed out again.
+        let lost = index_version.is_some_and(|index_version| index_version > version);
+        let reset = index_version.filter(|_| dropped || lost);
+        if let Some(index_version) = reset {
+            warn!(
+                "Change log <{}> is at version {} but the index at {}, resetting mirrors",
+                path.display(),
+                version,
+                index_version
+            );
+            events.retain(|event| event.id <= index_version);
+            // Versions keep increasing, so that no version ever stands for two indexes.
+            events.push(ChangeEvent {
+                id: version.max(index_version) + 1,
+                validator_account_id: String::new(),
+                block_height: 0,
+                timestamp: chrono::Utc::now().timestamp(),
+                added: Vec::new(),
+                removed: Vec::new(),
+                reset: true,
+            });
+            rewrite(&path, &events).await?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open change log <{}>", path.display()))?;
+
+        // A line cut short by a crash is terminated so that new events start on a line of
+        // their own.
+        if reset.is_none() && !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n").await?;
+        }
+
+        let synced = version_of(&events);
+        Ok(Self {
+            path,
+            retention: retention.max(1),
+            inner: Mutex::new(ChangeLogInner {
+                file,
+                events,
+                synced,
+            }),
+        })
+    }
+
+    /// Current version of the index as far as the synced events go, zero before the first
+    /// change.
+    pub async fn version(&self) -> u64 {
+        self.inner.lock().await.synced
+    }
+
+    /// Records a change of the delegators of `validator_account_id` and returns the version
+    /// of the index after it. Empty changes are not recorded.
+    ///
+    /// The change has to be applied to the index only if it was recorded: a failed append
+    /// leaves both the log and its version as they were. It is only served once synced.
+    pub async fn append(
+        &self,
+        validator_account_id: &str,
+        block_height: u64,
+        timestamp: i64,
+        added: Vec<String>,
+        removed: Vec<String>,
+    ) -> Result<u64> {
+        self.append_all(vec![Change {
+            validator_account_id: validator_account_id.to_string(),
+            block_height,
+            timestamp,
+            added,
+            removed,
+        }])
+        .await
+    }
+
+    /// Records several changes at once, e.g. of a full resync, either all of them or none.
+    pub async fn append_all(&self, changes: Vec<Change>) -> Result<u64> {
+        let mut inner = self.inner.lock().await;
+
+        let mut version = version_of(&inner.events);
+        let mut events = Vec::new();
+        let mut lines = Vec::new();
+        for change in changes {
+            if change.added.is_empty() && change.removed.is_empty() {
+                continue;
+            }
+
+            version += 1;
+            let event = ChangeEvent {
+                id: version,
+                validator_account_id: change.validator_account_id,
+                block_height: change.block_height,
+                timestamp: change.timestamp,
+                added: change.added,
+                removed: change.removed,
+                reset: false,
+            };
+            serde_json::to_writer(&mut lines, &event)?;
+            lines.push(b'\n');
+            events.push(event);
+        }
+
+        if events.is_empty() {
+            return Ok(version);
+        }
+
+        if let Err(e) = inner
+            .file
+            .write_all(&lines)
+            .await
+            .context("Failed to append to the change log")
+        {
+            // The file may end with a part of the lines, so it is written again without them.
+            if let Err(e) = self.reopen(&mut inner).await {
+                error!(
+                    "Failed to restore change log <{}>: {:#}",
+                    self.path.display(),
+                    e
+                );
+            }
+            return Err(e);
+        }
+        inner.events.extend(events);
+
+        // Compacted in batches, so that the file is not rewritten on every change.
+        if inner.events.len() >= 2 * self.retention {
+            let excess = inner.events.len() - self.retention;
+            inner.events.drain(..excess);
+            self.reopen(&mut inner).await?;
+
+            info!(
+                "Compacted change log <{}> to {} events",
+                self.path.display(),
+                inner.events.len()
+            );
+        }
+
+        Ok(version)
+    }
+
+    /// Makes every event appended so far durable and starts serving them. Returns the version
+    /// reached.
+    ///
+    /// The file is synced without holding the log, so that appends go on meanwhile. If it
+    /// cannot be, the file is written again from the events kept in memory.
+    pub async fn sync(&self) -> Result<u64> {
+        let (file, version) = {
+            let inner = self.inner.lock().await;
+            let version = version_of(&inner.events);
+            if inner.synced >= version {
+                return Ok(inner.synced);
+            }
+            let file = inner
+                .file
+                .try_clone()
+                .await
+                .context("Failed to open the change log to sync it")?;
+            (file, version)
+        };
+
+        let result = file
+            .sync_data()
+            .await
+            .context("Failed to sync the change log");
+
+        let mut inner = self.inner.lock().await;
+        if let Err(e) = result {
+            error!("{:#}, writing it again", e);
+            self.reopen(&mut inner).await?;
+        } else {
+            inner.synced = inner.synced.max(version);
+        }
+
+        Ok(inner.synced)
+    }
+
+    /// Rewrites the file with the events kept in memory, which makes all of them durable, and
+    /// appends to it from then on.
+    async fn reopen(&self, inner: &mut ChangeLogInner) -> Result<()> {
+        rewrite(&self.path, &inner.events).await?;
+        inner.synced = version_of(&inner.events);
+
+        inner.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .context("Failed to reopen the change log")?;
+
+        Ok(())
+    }
+
+    /// Mutations of up to `limit` events after `since_version`, or `None` if the events
+    /// following it are no longer kept, or were dropped by a reset, and the client has to
+    /// download the whole index again.
+    pub async fn sync_since(&self, since_version: u64, limit: usize) -> Option<SyncPage> {
+        let inner = self.inner.lock().await;
+        let synced_events = inner.synced_events();
+
+        let version = version_of(synced_events);
+        let first_kept_id = synced_events.first().map_or(version + 1, |event| event.id);
+        if since_version > version || since_version + 1 < first_kept_id {
+            return None;
+        }
+
+        let start = synced_events.partition_point(|event| event.id <= since_version);
+        let events = &synced_events[start..];
+        if events.iter().any(|event| event.reset) {
+            return None;
+        }
+        let returned = &events[..events.len().min(limit)];
+
+        let mutations = returned
+            .iter()
+            .flat_map(|event| {
+                let additions = event.added.iter().map(|account_id| Mutation {
+                    version: event.id,
+                    op: MutationKind::Add,
+                    account_id: account_id.clone(),
+                    staking_pool: event.validator_account_id.clone(),
+                });
+                let removals = event.removed.iter().map(|account_id| Mutation {
+                    version: event.id,
+                    op: MutationKind::Remove,
+                    account_id: account_id.clone(),
+                    staking_pool: event.validator_account_id.clone(),
+                });
+                additions.chain(removals)
+            })
+            .collect();
+
+        Some(SyncPage {
+            mutations,
+            version: returned.last().map_or(since_version, |event| event.id),
+            more: returned.len() < events.len(),
+        })
+    }
+
+    /// Up to `limit` events after the `since` cursor, optionally of a single staking pool.
+    pub async fn since(
+        &self,
+        since: u64,
+        validator_account_id: Option<&str>,
+        limit: usize,
+    ) -> ChangesPage {
+        let inner = self.inner.lock().await;
+        let synced_events = inner.synced_events();
+
+        let start = synced_events.partition_point(|event| event.id <= since);
+        let events: Vec<_> = synced_events[start..]
+            .iter()
+            .filter(|event| {
+                validator_account_id.is_none_or(|validator_account_id| {
+                    event.validator_account_id == validator_account_id
+                })
+            })
+            .take(limit)
+            .cloned()
+            .collect();
+
+        // A page that is not full means every matching event has been returned, so the
+        // cursor can move to the end of the log.
+        let next = if events.len() < limit {
+            synced_events
+                .last()
+                .map_or(since, |event| event.id.max(since))
+        } else {
+            events.last().map_or(since, |event| event.id)
+        };
+
+        ChangesPage { events, next }
+    }
+}
+
+/// Replaces the log file with `events`, through a temporary file synced and renamed over it.
+async fn rewrite(path: &Path, events: &[ChangeEvent]) -> Result<()> {
+    let mut content = Vec::new();
+    for event in events {
+        serde_json::to_writer(&mut content, event)?;
+        content.push(b'\n');
+    }
+
+    let temporary_path = path.with_extension("ndjson.tmp");
+    let mut file = tokio::fs::File::create(&temporary_path)
+        .await
+        .context("Failed to create the rewritten change log")?;
+    file.write_all(&content)
+        .await
+        .context("Failed to write the rewritten change log")?;
+    file.sync_all()
+        .await
+        .context("Failed to sync the rewritten change log")?;
+    tokio::fs::rename(&temporary_path, path)
+        .await
+        .context("Failed to move the rewritten change log in place")
+}
+
+fn version_of(events: &[ChangeEvent]) -> u64 {
+    events.last().map_or(0, |event| event.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn log_with_events(path: PathBuf, retention: usize, count: usize) -> ChangeLog {
+        let change_log = ChangeLog::open(path, retention, None).await.unwrap();
+        for n in 0..count {
+            change_log
+                .append(
+                    "pool.poolv1.near",
+                    100 + n as u64,
+                    0,
+                    vec![format!("delegator-{n}.near")],
+                    Vec::new(),
+                )
+                .await
+                .unwrap();
+            change_log.sync().await.unwrap();
+        }
+        change_log
+    }
+
+    #[tokio::test]
+    async fn sync_since_pages_through_mutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let change_log = log_with_events(dir.path().join("changes.ndjson"), 10, 3).await;
+
+        let page = change_log.sync_since(0, 2).await.unwrap();
+        assert_eq!(page.version, 2);
+        assert!(page.more);
+        assert_eq!(page.mutations.len(), 2);
+        assert_eq!(page.mutations[0].account_id, "delegator-0.near");
+
+        let page = change_log.sync_since(page.version, 2).await.unwrap();
+        assert_eq!(page.version, 3);
+        assert!(!page.more);
+
+        let page = change_log.sync_since(3, 2).await.unwrap();
+        assert_eq!(page.version, 3);
+        assert!(page.mutations.is_empty());
+
+        assert!(change_log.sync_since(4, 2).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sync_since_requires_full_resync_before_compacted_events() {
+        let dir = tempfile::tempdir().unwrap();
+        // The fourth event compacts the log down to events 3 and 4.
+        let change_log = log_with_events(dir.path().join("changes.ndjson"), 2, 4).await;
+
+        assert!(change_log.sync_since(1, 10).await.is_none());
+        let page = change_log.sync_since(2, 10).await.unwrap();
+        assert_eq!(page.version, 4);
+        assert_eq!(page.mutations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn events_newer_than_the_index_are_replaced_with_a_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.ndjson");
+        drop(log_with_events(path.clone(), 10, 3).await);
+
+        let change_log = ChangeLog::open(path.clone(), 10, Some(1)).await.unwrap();
+        assert_eq!(change_log.version().await, 4);
+        assert!(change_log.sync_since(0, 10).await.is_none());
+        assert!(change_log.sync_since(2, 10).await.is_none());
+        assert!(change_log
+            .sync_since(4, 10)
+            .await
+            .unwrap()
+            .mutations
+            .is_empty());
+        drop(change_log);
+
+        // Restarting before the index was written again does not call for another reset.
+        let change_log = ChangeLog::open(path.clone(), 10, Some(1)).await.unwrap();
+        assert_eq!(change_log.version().await, 4);
+
+        let version = change_log
+            .append(
+                "pool.poolv1.near",
+                200,
+                0,
+                vec!["late.near".into()],
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(version, 5);
+        assert_eq!(change_log.sync().await.unwrap(), 5);
+        drop(change_log);
+
+        // The reset is kept on disk, and a log that is not ahead of the index is left alone.
+        let change_log = ChangeLog::open(path, 10, Some(5)).await.unwrap();
+        let events = change_log.since(0, None, 10).await.events;
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.id, event.reset))
+                .collect::<Vec<_>>(),
+            [(1, false), (4, true), (5, false)]
+        );
+        assert!(change_log.sync_since(3, 10).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_log_behind_the_index_gets_a_reset_after_its_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.ndjson");
+        drop(log_with_events(path.clone(), 10, 2).await);
+
+        // The index was written at version 5, but the log lost the events after 2.
+        let change_log = ChangeLog::open(path, 10, Some(5)).await.unwrap();
+        assert_eq!(change_log.version().await, 6);
+        assert!(change_log.sync_since(2, 10).await.is_none());
+        assert!(change_log.sync_since(5, 10).await.is_none());
+        assert!(change_log
+            .sync_since(6, 10)
+            .await
+            .unwrap()
+            .mutations
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_are_served_once_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.ndjson");
+        let change_log = log_with_events(path.clone(), 10, 1).await;
+
+        let version = change_log
+            .append_all(vec![
+                Change {
+                    validator_account_id: "a.poolv1.near".to_string(),
+                    block_height: 200,
+                    timestamp: 0,
+                    added: vec!["new.near".to_string()],
+                    removed: Vec::new(),
+                },
+                Change {
+                    validator_account_id: "b.poolv1.near".to_string(),
+                    block_height: 200,
+                    timestamp: 0,
+                    added: Vec::new(),
+                    removed: Vec::new(),
+                },
+            ])
+            .await
+            .unwrap();
+        // Empty changes are not recorded.
+        assert_eq!(version, 2);
+        assert_eq!(change_log.version().await, 1);
+        assert_eq!(change_log.since(0, None, 10).await.next, 1);
+        assert!(change_log.sync_since(2, 10).await.is_none());
+
+        assert_eq!(change_log.sync().await.unwrap(), 2);
+        assert_eq!(change_log.version().await, 2);
+        assert_eq!(change_log.sync_since(1, 10).await.unwrap().version, 2);
+        drop(change_log);
+
+        let change_log = ChangeLog::open(path, 10, Some(2)).await.unwrap();
+        assert_eq!(change_log.version().await, 2);
+    }
+}
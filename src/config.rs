@@ -0,0 +1,515 @@
+use color_eyre::{eyre::Context, Result};
+use near_jsonrpc_client::JsonRpcClient;
+use rocket::figment::{providers::Env, Figment};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Key of the `Rocket.toml` table that holds the service configuration.
+pub const CONFIG_KEY: &str = "delegators";
+/// Prefix of the environment variables that override the configuration file,
+/// e.g. `DELEGATORS_NETWORKS=[mainnet,testnet]` or `DELEGATORS_TESTNET__RPC__QUERY=...`.
+pub const ENV_PREFIX: &str = "DELEGATORS_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Localnet,
+}
+
+impl Network {
+    const fn default_lookup_url(self) -> &'static str {
+        match self {
+            Self::Mainnet => "https://beta.rpc.mainnet.near.org",
+            Self::Testnet => "https://rpc.testnet.near.org",
+            Self::Localnet => "http://127.0.0.1:3030",
+        }
+    }
+
+    const fn default_query_url(self) -> &'static str {
+        match self {
+            Self::Mainnet => "https://rpc.mainnet.near.org",
+            Self::Testnet => "https://rpc.testnet.near.org",
+            Self::Localnet => "http://127.0.0.1:3030",
+        }
+    }
+
+    fn default_pool_factories(self) -> Vec<String> {
+        match self {
+            Self::Mainnet => vec!["poolv1.near".to_string()],
+            Self::Testnet => vec!["pool.f863973.m0".to_string()],
+            Self::Localnet => Vec::new(),
+        }
+    }
+
+    /// Mainnet keeps the original file name so existing caches are picked up.
+    fn default_cache_file(self, store: StoreKind) -> String {
+        let extension = match store {
+            StoreKind::Json => "json",
+            StoreKind::Sqlite => "sqlite",
+        };
+
+        match self {
+            Self::Mainnet => format!("delegators.{extension}"),
+            network => format!("delegators-{network}.{extension}"),
+        }
+    }
+
+    fn default_follower_cursor_file(self) -> String {
+        match self {
+            Self::Mainnet => "follower.json".to_string(),
+            network => format!("follower-{network}.json"),
+        }
+    }
+
+    fn default_receipts_file(self) -> String {
+        match self {
+            Self::Mainnet => "receipts.ndjson".to_string(),
+            network => format!("receipts-{network}.ndjson"),
+        }
+    }
+
+    fn default_evictions_file(self) -> String {
+        match self {
+            Self::Mainnet => "evictions.ndjson".to_string(),
+            network => format!("evictions-{network}.ndjson"),
+        }
+    }
+
+    fn default_changes_file(self) -> String {
+        match self {
+            Self::Mainnet => "changes.ndjson".to_string(),
+            network => format!("changes-{network}.ndjson"),
+        }
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" => Ok(Self::Mainnet),
+            "testnet" => Ok(Self::Testnet),
+            "localnet" => Ok(Self::Localnet),
+            _ => color_eyre::eyre::bail!("Unknown network <{s}>"),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mainnet => write!(f, "mainnet"),
+            Self::Testnet => write!(f, "testnet"),
+            Self::Localnet => write!(f, "localnet"),
+        }
+    }
+}
+
+/// Backend persisting the index, see [`crate::store`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// The whole index in a single JSON file.
+    #[default]
+    Json,
+    /// Delegator and validator rows in an embedded SQLite database.
+    Sqlite,
+}
+
+/// NEAR Lake-style storage the follower can read blocks from instead of the RPC.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LakeConfig {
+    /// Blocks in a local directory, e.g. fixtures or a synced copy of a bucket.
+    Directory { path: PathBuf },
+    /// Blocks in an S3-compatible bucket.
+    Bucket {
+        bucket: String,
+        #[serde(default = "default_lake_region")]
+        region: String,
+        /// Endpoint of an S3-compatible service such as MinIO.
+        endpoint: Option<String>,
+        /// Whether the bucket is billed to the requester, as the public NEAR Lake buckets are.
+        #[serde(default)]
+        requester_pays: bool,
+    },
+}
+
+fn default_lake_region() -> String {
+    "eu-central-1".to_string()
+}
+
+/// A single RPC server together with the headers it expects.
+///
+/// Can be written either as a plain URL string or as a table:
+/// `{ url = "...", api_key = "...", headers = { "x-foo" = "bar" } }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RawRpcEndpoint")]
+pub struct RpcEndpoint {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRpcEndpoint {
+    Url(String),
+    Full {
+        url: String,
+        #[serde(default)]
+        api_key: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+impl From<RawRpcEndpoint> for RpcEndpoint {
+    fn from(raw: RawRpcEndpoint) -> Self {
+        match raw {
+            RawRpcEndpoint::Url(url) => Self::from(url),
+            RawRpcEndpoint::Full {
+                url,
+                api_key,
+                headers,
+            } => Self {
+                url,
+                api_key,
+                headers,
+            },
+        }
+    }
+}
+
+impl From<String> for RpcEndpoint {
+    fn from(url: String) -> Self {
+        Self {
+            url,
+            api_key: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl RpcEndpoint {
+    pub fn connect(&self) -> Result<JsonRpcClient> {
+        let mut json_rpc_client = JsonRpcClient::connect(&self.url);
+
+        if let Some(api_key) = &self.api_key {
+            json_rpc_client = json_rpc_client.header(
+                near_jsonrpc_client::auth::ApiKey::new(api_key)
+                    .with_context(|| format!("Invalid API key for <{}>", self.url))?,
+            );
+        }
+
+        for (name, value) in &self.headers {
+            json_rpc_client.headers_mut().insert(
+                near_jsonrpc_client::header::HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid header name <{name}>"))?,
+                near_jsonrpc_client::header::HeaderValue::from_str(value)
+                    .with_context(|| format!("Invalid value for header <{name}>"))?,
+            );
+        }
+
+        Ok(json_rpc_client)
+    }
+}
+
+/// One endpoint or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawRpcEndpoints {
+    One(RpcEndpoint),
+    Many(Vec<RpcEndpoint>),
+}
+
+impl From<RawRpcEndpoints> for Vec<RpcEndpoint> {
+    fn from(raw: RawRpcEndpoints) -> Self {
+        match raw {
+            RawRpcEndpoints::One(endpoint) => vec![endpoint],
+            RawRpcEndpoints::Many(endpoints) => endpoints,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawRpcConfig {
+    lookup: Option<RawRpcEndpoints>,
+    query: Option<RawRpcEndpoints>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RawNetworkConfig {
+    #[serde(default)]
+    rpc: RawRpcConfig,
+    pool_factories: Option<Vec<String>>,
+    #[serde(default)]
+    staking_pool_code_hashes: Vec<near_primitives::hash::CryptoHash>,
+    #[serde(default)]
+    store: StoreKind,
+    cache_file: Option<String>,
+    cache_snapshots: Option<usize>,
+    changes_file: Option<String>,
+    changes_retention: Option<usize>,
+    receipts_file: Option<String>,
+    receipts_retention: Option<usize>,
+    #[serde(default)]
+    lookups_from_store: bool,
+    #[serde(default, with = "near_primitives::serialize::dec_format")]
+    min_delegation: Option<near_primitives::types::Balance>,
+    history_dir: Option<String>,
+    history_interval: Option<u64>,
+    history_retention: Option<usize>,
+    #[serde(default)]
+    follower: bool,
+    follower_cursor_file: Option<String>,
+    follower_poll_interval: Option<u64>,
+    follower_start_height: Option<u64>,
+    lake: Option<LakeConfig>,
+    atomic_resync: Option<bool>,
+    eviction_grace_period: Option<u64>,
+    evictions_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawConfig {
+    #[serde(default = "default_networks")]
+    networks: Vec<Network>,
+    cache_dir: Option<PathBuf>,
+    auth_token: Option<String>,
+    webhook_secret: Option<String>,
+    signature_tolerance: Option<u64>,
+    #[serde(default)]
+    mainnet: RawNetworkConfig,
+    #[serde(default)]
+    testnet: RawNetworkConfig,
+    #[serde(default)]
+    localnet: RawNetworkConfig,
+}
+
+fn default_networks() -> Vec<Network> {
+    vec![Network::Mainnet]
+}
+
+/// RPC servers split by the role they play. Each role is served by a pool of
+/// interchangeable endpoints.
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    /// Resolves webhook receipts and block hashes, and discovers staking pools.
+    pub lookup: Vec<RpcEndpoint>,
+    /// Queries delegators of the staking pools at a given block height.
+    pub query: Vec<RpcEndpoint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub network: Network,
+    pub rpc: RpcConfig,
+    /// Factory accounts whose state lists the deployed staking pools.
+    pub pool_factories: Vec<String>,
+    /// Hashes of staking pool contracts, so that pools not deployed by the factories are
+    /// accepted too.
+    pub staking_pool_code_hashes: Vec<near_primitives::hash::CryptoHash>,
+    pub store: StoreKind,
+    /// Path of the delegators cache file (or SQLite database) of this network.
+    pub cache_path: PathBuf,
+    /// Number of previous cache files kept next to it to recover from a corrupted one.
+    pub cache_snapshots: usize,
+    /// Path of the log of delegators joining and leaving staking pools.
+    pub changes_path: PathBuf,
+    /// Number of change events kept for `/changes` and incremental sync.
+    pub changes_retention: usize,
+    /// Path of the receipts already received by the webhook, with what they resolved to.
+    pub receipts_path: PathBuf,
+    /// Number of receipts remembered to recognize repeated deliveries.
+    pub receipts_retention: usize,
+    /// Answers lookups by account from the store, which then has to be `sqlite`, instead of
+    /// keeping the delegator -> staking pools direction of the index in memory.
+    pub lookups_from_store: bool,
+    /// Positions with less than this staked and unstaked balance in total (in yoctoNEAR) are
+    /// considered dust and hidden unless explicitly requested. The default of 1 hides only
+    /// accounts that have withdrawn everything.
+    pub min_delegation: near_primitives::types::Balance,
+    /// Directory of the historical snapshots served to point-in-time queries.
+    pub history_dir: PathBuf,
+    /// Seconds between two historical snapshots, roughly an epoch by default.
+    /// Zero disables them.
+    pub history_interval: u64,
+    /// Number of historical snapshots kept, about a year's worth by default. `None` keeps all
+    /// of them, set with zero.
+    pub history_retention: Option<usize>,
+    /// Follows the chain to find staking pools to refresh, instead of relying on webhooks
+    /// only.
+    pub follower: bool,
+    /// Path of the last block height processed by the follower.
+    pub follower_cursor_path: PathBuf,
+    /// Seconds to wait for new blocks once the follower has caught up.
+    pub follower_poll_interval: u64,
+    /// First block the follower processes when there is no cursor yet, the latest one if
+    /// unset. Required with `lake`, which cannot tell its latest block without being listed
+    /// from the start.
+    pub follower_start_height: Option<u64>,
+    /// Where the follower reads blocks from, the lookup RPC if unset.
+    pub lake: Option<LakeConfig>,
+    /// Full refreshes read every staking pool at one block height off to the side and
+    /// replace the index at once, instead of queueing every staking pool to the worker.
+    pub atomic_resync: bool,
+    /// Seconds a known staking pool has to be missing from the pool factories (and from the
+    /// `staking_pool_code_hashes` contracts) before it is evicted from the index.
+    pub eviction_grace_period: u64,
+    /// Path of the log of evicted staking pools.
+    pub evictions_path: PathBuf,
+}
+
+impl NetworkConfig {
+    fn resolve(network: Network, raw: RawNetworkConfig, cache_dir: &Path) -> Self {
+        Self {
+            network,
+            rpc: RpcConfig {
+                lookup: raw.rpc.lookup.map_or_else(
+                    || vec![network.default_lookup_url().to_string().into()],
+                    Vec::from,
+                ),
+                query: raw.rpc.query.map_or_else(
+                    || vec![network.default_query_url().to_string().into()],
+                    Vec::from,
+                ),
+            },
+            pool_factories: raw
+                .pool_factories
+                .unwrap_or_else(|| network.default_pool_factories()),
+            staking_pool_code_hashes: raw.staking_pool_code_hashes,
+            store: raw.store,
+            cache_path: cache_dir.join(
+                raw.cache_file
+                    .unwrap_or_else(|| network.default_cache_file(raw.store)),
+            ),
+            cache_snapshots: raw.cache_snapshots.unwrap_or(3),
+            changes_path: cache_dir.join(
+                raw.changes_file
+                    .unwrap_or_else(|| network.default_changes_file()),
+            ),
+            changes_retention: raw.changes_retention.unwrap_or(100_000),
+            receipts_path: cache_dir.join(
+                raw.receipts_file
+                    .unwrap_or_else(|| network.default_receipts_file()),
+            ),
+            receipts_retention: raw.receipts_retention.unwrap_or(100_000),
+            lookups_from_store: raw.lookups_from_store,
+            min_delegation: raw.min_delegation.unwrap_or(1),
+            history_dir: cache_dir.join(
+                raw.history_dir
+                    .unwrap_or_else(|| format!("history/{network}")),
+            ),
+            history_interval: raw.history_interval.unwrap_or(12 * 60 * 60),
+            history_retention: match raw.history_retention {
+                None => Some(730),
+                Some(0) => None,
+                Some(retention) => Some(retention),
+            },
+            follower: raw.follower,
+            follower_cursor_path: cache_dir.join(
+                raw.follower_cursor_file
+                    .unwrap_or_else(|| network.default_follower_cursor_file()),
+            ),
+            follower_poll_interval: raw.follower_poll_interval.unwrap_or(1),
+            follower_start_height: raw.follower_start_height,
+            lake: raw.lake,
+            atomic_resync: raw.atomic_resync.unwrap_or(false),
+            eviction_grace_period: raw.eviction_grace_period.unwrap_or(24 * 60 * 60),
+            evictions_path: cache_dir.join(
+                raw.evictions_file
+                    .unwrap_or_else(|| network.default_evictions_file()),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Networks indexed by this process, each with its own RPC servers and cache file.
+    pub networks: BTreeMap<Network, NetworkConfig>,
+    pub auth: AuthConfig,
+}
+
+/// Credentials required by the routes that change the index. They are open to anyone when
+/// neither is set.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// Shared secret accepted as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Key of the HMAC-SHA256 signatures of request bodies.
+    pub webhook_secret: Option<String>,
+    /// Seconds a signed request stays valid after (or before) its timestamp.
+    pub signature_tolerance: u64,
+}
+
+impl From<RawConfig> for Config {
+    fn from(mut raw: RawConfig) -> Self {
+        let cache_dir = raw.cache_dir.unwrap_or_else(|| PathBuf::from("/mnt"));
+
+        let networks = raw
+            .networks
+            .iter()
+            .map(|&network| {
+                let raw_network = std::mem::take(match network {
+                    Network::Mainnet => &mut raw.mainnet,
+                    Network::Testnet => &mut raw.testnet,
+                    Network::Localnet => &mut raw.localnet,
+                });
+
+                (
+                    network,
+                    NetworkConfig::resolve(network, raw_network, &cache_dir),
+                )
+            })
+            .collect();
+
+        Self {
+            networks,
+            auth: AuthConfig {
+                token: raw.auth_token,
+                webhook_secret: raw.webhook_secret,
+                signature_tolerance: raw.signature_tolerance.unwrap_or(300),
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Reads the `[delegators]` table from Rocket's figment (`Rocket.toml` and `ROCKET_*`
+    /// variables) and applies `DELEGATORS_*` environment overrides on top of it.
+    pub fn from_figment(figment: &Figment) -> Result<Self> {
+        let raw = figment
+            .focus(CONFIG_KEY)
+            .merge(Env::prefixed(ENV_PREFIX).split("__").global())
+            .extract::<RawConfig>()
+            .context("Failed to parse configuration")?;
+
+        if raw.networks.is_empty() {
+            color_eyre::eyre::bail!("At least one network has to be configured");
+        }
+
+        let config = Self::from(raw);
+        for (network, network_config) in &config.networks {
+            if network_config.follower
+                && network_config.lake.is_some()
+                && network_config.follower_start_height.is_none()
+            {
+                color_eyre::eyre::bail!(
+                    "{network}.follower_start_height has to be set to follow a lake"
+                );
+            }
+            if network_config.lookups_from_store && network_config.store != StoreKind::Sqlite {
+                color_eyre::eyre::bail!("{network}.lookups_from_store requires the sqlite store");
+            }
+        }
+
+        Ok(config)
+    }
+}
@@ -1,38 +1,241 @@
+use crate::changelog::Change;
+use crate::index;
+use crate::interner::{Id, Interner};
 use crate::methods;
+use crate::network::NetworkState;
 
 use color_eyre::{eyre::Context, Result};
-use near_jsonrpc_client::JsonRpcClient;
 use std::collections::{BTreeMap, BTreeSet};
-
+use std::ops::Bound;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+/// Position of a delegator in a staking pool, as reported by the pool's `get_accounts` method.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct DelegatorStake {
+    #[serde(with = "near_primitives::serialize::dec_format")]
+    pub staked_balance: near_primitives::types::Balance,
+    #[serde(with = "near_primitives::serialize::dec_format")]
+    pub unstaked_balance: near_primitives::types::Balance,
+    pub can_withdraw: bool,
+}
+
+impl DelegatorStake {
+    pub const fn total(&self) -> near_primitives::types::Balance {
+        self.staked_balance.saturating_add(self.unstaked_balance)
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidatorFailure {
+    pub timestamp: i64,
+    pub block_height: u64,
+    pub error: String,
+}
 
-pub const DELEGATORS_FILENAME: &str = "delegators.json";
+/// Freshness of a single staking pool's data.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidatorMetadata {
+    /// Block height the delegators were read at.
+    pub block_height: Option<u64>,
+    /// When the delegators were read.
+    pub timestamp: Option<i64>,
+    /// Most recent refresh that failed, kept after later successful refreshes.
+    pub last_failure: Option<ValidatorFailure>,
+}
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde")]
 pub struct ValidatorsWithTimestamp {
     pub timestamp: i64,
-    pub validator_staking_pools: BTreeMap<String, BTreeSet<String>>,
+    pub validator_staking_pools: index::ByStakingPool<DelegatorStake>,
+    #[serde(default)]
+    pub validators_metadata: BTreeMap<Id, ValidatorMetadata>,
 }
 
+impl ValidatorsWithTimestamp {
+    /// Replaces the account ids with the shared copies of `interner`, e.g. after loading the
+    /// index from the store.
+    pub fn interned(self, interner: &Interner) -> Self {
+        Self {
+            timestamp: self.timestamp,
+            validator_staking_pools: self
+                .validator_staking_pools
+                .into_iter()
+                .map(|(validator, delegators)| {
+                    (
+                        interner.intern(&validator),
+                        Arc::new(interner.intern_keys(Arc::unwrap_or_clone(delegators))),
+                    )
+                })
+                .collect(),
+            validators_metadata: interner.intern_keys(self.validators_metadata),
+        }
+    }
+
+    /// Metadata of the given staking pools, skipping unknown ones.
+    pub fn metadata_of<'a>(
+        &self,
+        validators: impl IntoIterator<Item = &'a Id>,
+    ) -> BTreeMap<Id, ValidatorMetadata> {
+        validators
+            .into_iter()
+            .filter_map(|validator| {
+                self.validators_metadata
+                    .get(validator)
+                    .map(|metadata| (validator.clone(), metadata.clone()))
+            })
+            .collect()
+    }
+
+    /// Dust threshold of the positions in `validator`. Staking pools that were never read at a
+    /// known block come from a cache written before balances were tracked, so their balances
+    /// are unknown rather than zero and none of their positions is left out.
+    pub fn min_delegation_of(
+        &self,
+        validator: &str,
+        min_delegation: near_primitives::types::Balance,
+    ) -> near_primitives::types::Balance {
+        match self.validators_metadata.get(validator) {
+            Some(ValidatorMetadata {
+                block_height: Some(_),
+                ..
+            }) => min_delegation,
+            _ => 0,
+        }
+    }
+
+    /// Positions of `account_id` in every staking pool, leaving out those whose balance is
+    /// below `min_delegation`.
+    pub fn delegator_stakes(
+        &self,
+        account_id: &str,
+        min_delegation: near_primitives::types::Balance,
+    ) -> BTreeMap<Id, DelegatorStake> {
+        self.validator_staking_pools
+            .iter()
+            .filter_map(|(validator, delegators)| {
+                delegators
+                    .get(account_id)
+                    .filter(|stake| {
+                        stake.total() >= self.min_delegation_of(validator, min_delegation)
+                    })
+                    .map(|stake| (validator.clone(), *stake))
+            })
+            .collect()
+    }
+
+    pub fn summary(
+        &self,
+        min_delegation: near_primitives::types::Balance,
+    ) -> ValidatorsSummaryWithTimestamp {
+        ValidatorsSummaryWithTimestamp {
+            timestamp: self.timestamp,
+            version: None,
+            validators: self
+                .validator_staking_pools
+                .iter()
+                .map(|(validator, delegators)| {
+                    (
+                        validator.clone(),
+                        count_delegators(
+                            delegators,
+                            self.min_delegation_of(validator, min_delegation),
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Up to `limit` delegators of `validator_account_id` that come after `after`, or `None`
+    /// if the staking pool is unknown.
+    pub fn validator_delegators(
+        &self,
+        validator_account_id: &str,
+        after: Option<&str>,
+        limit: usize,
+        min_delegation: near_primitives::types::Balance,
+    ) -> Option<ValidatorDelegatorsWithTimestamp> {
+        let delegators = self.validator_staking_pools.get(validator_account_id)?;
+        let min_delegation = self.min_delegation_of(validator_account_id, min_delegation);
+
+        let lower_bound = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut page = delegators
+            .range::<str, _>((lower_bound, Bound::Unbounded))
+            .filter(|(_, stake)| stake.total() >= min_delegation)
+            .take(limit + 1)
+            .map(|(delegator, stake)| (delegator.clone(), *stake))
+            .collect::<BTreeMap<_, _>>();
+
+        let next = if page.len() > limit {
+            page.pop_last();
+            page.last_key_value()
+                .map(|(delegator, _)| delegator.clone())
+        } else {
+            None
+        };
+
+        Some(ValidatorDelegatorsWithTimestamp {
+            timestamp: self.timestamp,
+            version: None,
+            delegators: page,
+            next,
+        })
+    }
+
+    pub fn validator_delegators_count(
+        &self,
+        validator_account_id: &str,
+        min_delegation: near_primitives::types::Balance,
+    ) -> Option<ValidatorDelegatorsCountWithTimestamp> {
+        let delegators = self.validator_staking_pools.get(validator_account_id)?;
+        let min_delegation = self.min_delegation_of(validator_account_id, min_delegation);
+
+        Some(ValidatorDelegatorsCountWithTimestamp {
+            timestamp: self.timestamp,
+            version: None,
+            count: count_delegators(delegators, min_delegation),
+        })
+    }
+}
+
+fn count_delegators(
+    delegators: &BTreeMap<Id, DelegatorStake>,
+    min_delegation: near_primitives::types::Balance,
+) -> usize {
+    delegators
+        .values()
+        .filter(|stake| stake.total() >= min_delegation)
+        .count()
+}
+
+/// Only used to load caches written before balances were tracked, so every stake is unknown
+/// and the timestamp is reset to get the whole index refreshed. The staking pools get no
+/// metadata, which keeps their positions out of the dust filter until then, see
+/// [`ValidatorsWithTimestamp::min_delegation_of`].
 impl From<&DelegatorsWithTimestamp> for ValidatorsWithTimestamp {
     fn from(delegators: &DelegatorsWithTimestamp) -> Self {
-        let mut validators_map = BTreeMap::<String, BTreeSet<String>>::new();
+        let mut validators_map = BTreeMap::<Id, BTreeMap<Id, DelegatorStake>>::new();
 
         for (delegator, validators) in &delegators.delegator_staking_pools {
             for validator in validators {
                 validators_map
-                    .entry(validator.to_string())
+                    .entry(validator.clone())
                     .or_default()
-                    .insert(delegator.clone());
+                    .insert(delegator.clone(), DelegatorStake::default());
             }
         }
 
         Self {
-            timestamp: delegators.timestamp,
-            validator_staking_pools: validators_map,
+            timestamp: 0,
+            validator_staking_pools: validators_map
+                .into_iter()
+                .map(|(validator, delegators)| (validator, Arc::new(delegators)))
+                .collect(),
+            validators_metadata: BTreeMap::new(),
         }
     }
 }
@@ -41,142 +244,618 @@ impl From<&DelegatorsWithTimestamp> for ValidatorsWithTimestamp {
 #[serde(crate = "rocket::serde")]
 pub struct DelegatorsWithTimestamp {
     pub timestamp: i64,
-    pub delegator_staking_pools: BTreeMap<String, BTreeSet<String>>,
+    /// Version of the served index, see [`crate::changelog::ChangeLog`]. Absent for views
+    /// built from a historical snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub delegator_staking_pools: index::ByDelegator,
+    #[serde(default)]
+    pub staking_pools_metadata: BTreeMap<Id, ValidatorMetadata>,
 }
 
-impl From<&ValidatorsWithTimestamp> for DelegatorsWithTimestamp {
-    fn from(validators: &ValidatorsWithTimestamp) -> Self {
-        let mut delegators_map = BTreeMap::<String, BTreeSet<String>>::new();
-
-        for (validator, delegators) in &validators.validator_staking_pools {
-            for delegator in delegators {
-                delegators_map
-                    .entry(delegator.to_string())
-                    .or_default()
-                    .insert(validator.clone());
-            }
-        }
-
+impl DelegatorsWithTimestamp {
+    /// Builds the delegator -> staking pools index, leaving out positions whose balance is
+    /// below `min_delegation`.
+    pub fn from_validators(
+        validators: &ValidatorsWithTimestamp,
+        min_delegation: near_primitives::types::Balance,
+    ) -> Self {
         Self {
             timestamp: validators.timestamp,
-            delegator_staking_pools: delegators_map,
+            version: None,
+            delegator_staking_pools: index::build_by_delegator(
+                &validators.validator_staking_pools,
+                |validator, stake| {
+                    stake.total() >= validators.min_delegation_of(validator, min_delegation)
+                },
+            ),
+            staking_pools_metadata: validators.validators_metadata.clone(),
         }
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone)]
-#[serde(crate = "rocket::serde")]
-pub struct DelegatorWithTimestamp {
-    pub timestamp: i64,
-    pub delegator_staking_pools: BTreeSet<String>,
+/// Restricts which delegators are listed: only account ids after `after` that start with
+/// `prefix` and end with `suffix`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DelegatorsFilter<'a> {
+    pub after: Option<&'a str>,
+    pub prefix: Option<&'a str>,
+    pub suffix: Option<&'a str>,
 }
 
-pub async fn with_json_file_cache() -> Result<tokio::fs::File> {
-    let path = format!("/mnt/{DELEGATORS_FILENAME}");
+impl DelegatorsWithTimestamp {
+    pub fn iter_filtered<'a>(
+        &'a self,
+        filter: DelegatorsFilter<'a>,
+    ) -> impl Iterator<Item = (&'a Id, &'a BTreeSet<Id>)> + 'a {
+        let prefix = filter.prefix.unwrap_or_default();
+        let suffix = filter.suffix.unwrap_or_default();
+
+        // Account ids are sorted, so every match of the prefix is within one range.
+        let lower_bound = match filter.after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+
+        self.delegator_staking_pools
+            .range::<_, str>((lower_bound, Bound::Unbounded))
+            .take_while(move |(delegator, _)| delegator.starts_with(prefix))
+            .filter(move |(delegator, _)| delegator.ends_with(suffix))
+    }
 
-    tokio::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(path)
-        .await
-        .context("Failed to open file")
+    pub fn page(&self, filter: DelegatorsFilter, limit: usize) -> DelegatorsPageWithTimestamp {
+        let mut delegator_staking_pools = self
+            .iter_filtered(filter)
+            .take(limit + 1)
+            .map(|(delegator, validators)| (delegator.clone(), validators.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        let next = if delegator_staking_pools.len() > limit {
+            delegator_staking_pools.pop_last();
+            delegator_staking_pools
+                .last_key_value()
+                .map(|(delegator, _)| delegator.clone())
+        } else {
+            None
+        };
+
+        let staking_pools_metadata = delegator_staking_pools
+            .values()
+            .flatten()
+            .filter_map(|validator| {
+                self.staking_pools_metadata
+                    .get(validator)
+                    .map(|metadata| (validator.clone(), metadata.clone()))
+            })
+            .collect();
+
+        DelegatorsPageWithTimestamp {
+            timestamp: self.timestamp,
+            version: self.version,
+            delegator_staking_pools,
+            staking_pools_metadata,
+            next,
+        }
+    }
 }
 
-pub async fn get_delegators_from_cache() -> Result<DelegatorsWithTimestamp> {
-    let mut content = String::new();
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DelegatorsPageWithTimestamp {
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub delegator_staking_pools: BTreeMap<Id, BTreeSet<Id>>,
+    /// Metadata of the staking pools listed on this page.
+    pub staking_pools_metadata: BTreeMap<Id, ValidatorMetadata>,
+    /// Cursor for the next page, absent on the last one.
+    pub next: Option<Id>,
+}
 
-    let mut file = with_json_file_cache().await?;
-    file.read_to_string(&mut content)
-        .await
-        .context("Failed to read from file")?;
+/// Line of the NDJSON dump.
+#[derive(Debug, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DelegatorStakingPools<'a> {
+    pub account_id: &'a str,
+    pub staking_pools: &'a BTreeSet<Id>,
+}
 
-    Ok(serde_json::from_str(&content).map_or_else(
-        |_| {
-            info!("File is empty");
-            DelegatorsWithTimestamp::default()
-        },
-        |data| data,
-    ))
+#[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DelegatorWithTimestamp {
+    pub timestamp: i64,
+    /// Version of the snapshot the response was read from, absent for historical ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub delegator_staking_pools: BTreeSet<Id>,
+    pub balances: BTreeMap<Id, DelegatorStake>,
+    pub staking_pools_metadata: BTreeMap<Id, ValidatorMetadata>,
 }
 
-pub async fn update_delegators_cache(
-    delegators_with_timestamp: &Arc<RwLock<DelegatorsWithTimestamp>>,
-) -> Result<()> {
-    let updated_delegators_json =
-        serde_json::to_string_pretty(&delegators_with_timestamp.read().await.clone())?;
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidatorsSummaryWithTimestamp {
+    pub timestamp: i64,
+    /// Version of the snapshot the response was read from, absent for historical ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Number of delegators of every staking pool.
+    pub validators: BTreeMap<Id, usize>,
+}
 
-    let mut file = with_json_file_cache().await?;
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidatorDelegatorsWithTimestamp {
+    pub timestamp: i64,
+    /// Version of the snapshot the response was read from, absent for historical ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub delegators: BTreeMap<Id, DelegatorStake>,
+    /// Cursor for the next page, absent on the last one.
+    pub next: Option<Id>,
+}
 
-    file.seek(std::io::SeekFrom::Start(0))
-        .await
-        .context("Failed to seek to the beginning of the file")?;
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidatorDelegatorsCountWithTimestamp {
+    pub timestamp: i64,
+    /// Version of the snapshot the response was read from, absent for historical ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    pub count: usize,
+}
 
-    file.set_len(0)
-        .await
-        .context("Failed to truncate the file")?;
+/// Reads the delegators of `validator_account_id` at `block_id`, retrying failed calls.
+pub async fn fetch_delegators(
+    network_state: &NetworkState,
+    validator_account_id: &str,
+    block_id: u64,
+) -> Result<BTreeMap<Id, DelegatorStake>> {
+    let block_reference = near_primitives::types::BlockReference::BlockId(
+        near_primitives::types::BlockId::Height(block_id),
+    );
+
+    let mut last_error = None;
 
-    file.write_all(updated_delegators_json.as_bytes())
+    for _ in 0..methods::ATTEMPTS {
+        match methods::get_delegators_by_validator_account_id(
+            &network_state.query_rpc_pool,
+            validator_account_id.to_string(),
+            block_reference.clone(),
+        )
         .await
-        .context("Failed to write to file")?;
+        {
+            Ok(validator_delegators) => {
+                return Ok(network_state.interner.intern_keys(validator_delegators));
+            }
+            Err(e) => last_error = Some(e),
+        }
 
-    info!("Updated delegators file");
+        warn!(
+            "Failed to get delegators for validator_account_id: {}. Retrying...",
+            validator_account_id
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    }
 
-    Ok(())
+    Err(last_error.unwrap_or_else(|| {
+        color_eyre::eyre::eyre!(
+            "Failed to get delegators for validator_account_id: {}",
+            validator_account_id
+        )
+    }))
 }
 
 pub async fn update_delegators_by_validator_account_id(
-    json_rpc_client: &JsonRpcClient,
-    delegators_with_timestamp: &Arc<RwLock<DelegatorsWithTimestamp>>,
-    validators_with_timestamp: &Arc<RwLock<ValidatorsWithTimestamp>>,
+    network_state: &NetworkState,
     validator_account_id: String,
     block_id: u64,
 ) -> Result<()> {
+    let staking_pool = network_state.interner.intern(&validator_account_id);
+
     info!(
         "Updating delegators for validator: {}",
         validator_account_id
     );
 
-    let block_reference = near_primitives::types::BlockReference::BlockId(
-        near_primitives::types::BlockId::Height(block_id),
-    );
+    let validator_delegators =
+        match fetch_delegators(network_state, &validator_account_id, block_id).await {
+            Ok(validator_delegators) => validator_delegators,
+            Err(e) => {
+                record_failure(network_state, &staking_pool, block_id, &e).await;
+                color_eyre::eyre::bail!(
+                    "Failed to get delegators for validator_account_id: {}",
+                    validator_account_id
+                )
+            }
+        };
 
-    for _ in 0..methods::ATTEMPTS {
-        if let Ok(validator_delegators) = methods::get_delegators_by_validator_account_id(
-            json_rpc_client,
-            validator_account_id.clone(),
-            block_reference.clone(),
+    apply_refresh(network_state, staking_pool, block_id, validator_delegators).await
+}
+
+/// Replaces the delegators of `staking_pool` with the ones read at `block_id`, unless the
+/// index already holds ones read at a newer block.
+async fn apply_refresh(
+    network_state: &NetworkState,
+    staking_pool: Id,
+    block_id: u64,
+    validator_delegators: BTreeMap<Id, DelegatorStake>,
+) -> Result<()> {
+    let delegators_with_timestamp = &network_state.delegators_state;
+    let validators_with_timestamp = &network_state.validators_state;
+    let min_delegation = network_state.config.min_delegation;
+
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut validators_with_timestamp = validators_with_timestamp.write().await;
+
+    // A refresh at an older block than the index, e.g. of a follower catching up, finished
+    // after a newer one and would undo it.
+    let mut metadata = validators_with_timestamp
+        .validators_metadata
+        .get(&staking_pool)
+        .cloned()
+        .unwrap_or_default();
+    if let Some(block_height) = metadata
+        .block_height
+        .filter(|block_height| *block_height > block_id)
+    {
+        info!(
+            "Dropped delegators of {} read at block {}, the index is at block {}",
+            staking_pool, block_id, block_height
+        );
+        return Ok(());
+    }
+    metadata.block_height = Some(block_id);
+    metadata.timestamp = Some(timestamp);
+
+    // Recorded under the write lock so that the log follows the order in which the index
+    // changes. The index is only changed once the change is recorded, so that mirrors never
+    // miss it. The worker syncs the log once for the whole batch, before publishing it.
+    let previous_min_delegation =
+        validators_with_timestamp.min_delegation_of(&staking_pool, min_delegation);
+    let (added, removed) = index::diff(
+        validators_with_timestamp
+            .validator_staking_pools
+            .get(&staking_pool)
+            .map(Arc::as_ref),
+        &validator_delegators,
+        |stake| stake.total() >= previous_min_delegation,
+        |stake| stake.total() >= min_delegation,
+    );
+    let version = match network_state
+        .change_log
+        .append(
+            &staking_pool,
+            block_id,
+            timestamp,
+            added.iter().map(ToString::to_string).collect(),
+            removed.iter().map(ToString::to_string).collect(),
         )
         .await
+    {
+        Ok(version) => version,
+        Err(e) => {
+            drop(validators_with_timestamp);
+            let e = e.wrap_err("Failed to record changes");
+            record_failure(network_state, &staking_pool, block_id, &e).await;
+            return Err(e.wrap_err(format!(
+                "Failed to update delegators for validator_account_id: {staking_pool}"
+            )));
+        }
+    };
+
+    // Taken before the validators are released, so that concurrent refreshes change both
+    // directions of the index in the same order.
+    let mut locked_delegators = delegators_with_timestamp.write().await;
+
+    validators_with_timestamp.timestamp = timestamp;
+    validators_with_timestamp
+        .validator_staking_pools
+        .insert(staking_pool.clone(), Arc::new(validator_delegators));
+    validators_with_timestamp
+        .validators_metadata
+        .insert(staking_pool.clone(), metadata.clone());
+    drop(validators_with_timestamp);
+
+    // Only the delegators that joined or left are touched, instead of rebuilding the whole
+    // delegator -> staking pools direction. It is not kept at all when lookups by account are
+    // answered from the store.
+    if !network_state.config.lookups_from_store {
+        index::apply_diff(
+            &mut locked_delegators.delegator_staking_pools,
+            &staking_pool,
+            &added,
+            &removed,
+        );
+    }
+    locked_delegators.timestamp = timestamp;
+    locked_delegators.version = Some(version);
+    locked_delegators
+        .staking_pools_metadata
+        .insert(staking_pool.clone(), metadata);
+    drop(locked_delegators);
+
+    info!("Updated delegators for validator: {}", staking_pool);
+
+    Ok(())
+}
+
+/// Keeps the error of a refresh of `staking_pool` in its metadata.
+async fn record_failure(
+    network_state: &NetworkState,
+    staking_pool: &Id,
+    block_id: u64,
+    error: &color_eyre::Report,
+) {
+    let failure = ValidatorFailure {
+        timestamp: chrono::Utc::now().timestamp(),
+        block_height: block_id,
+        error: format!("{error:#}"),
+    };
+
+    let mut validators_with_timestamp = network_state.validators_state.write().await;
+    let metadata = validators_with_timestamp
+        .validators_metadata
+        .entry(staking_pool.clone())
+        .or_default();
+    metadata.last_failure = Some(failure);
+    let metadata = metadata.clone();
+    drop(validators_with_timestamp);
+
+    network_state
+        .delegators_state
+        .write()
+        .await
+        .staking_pools_metadata
+        .insert(staking_pool.clone(), metadata);
+}
+
+/// Replaces the staking pools read by a full resync at `block_height` in one go. Staking pools
+/// that were refreshed at a newer block in the meantime are left as they are, as if those
+/// refreshes were applied again on top of the resync. Nothing is replaced if the changes can't
+/// be recorded. Like refreshes, the changes are synced by the worker before it publishes them.
+///
+/// Returns the staking pools that were replaced and the ones that were left alone.
+pub async fn apply_resync(
+    network_state: &NetworkState,
+    block_height: u64,
+    resynced: BTreeMap<Id, BTreeMap<Id, DelegatorStake>>,
+) -> Result<(Vec<Id>, Vec<Id>)> {
+    let min_delegation = network_state.config.min_delegation;
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let mut validators_with_timestamp = network_state.validators_state.write().await;
+
+    let mut diffs = Vec::new();
+    let mut newer = Vec::new();
+    for (staking_pool, validator_delegators) in resynced {
+        if validators_with_timestamp
+            .validators_metadata
+            .get(&staking_pool)
+            .and_then(|metadata| metadata.block_height)
+            .is_some_and(|previous_block_height| previous_block_height > block_height)
         {
-            let timestamp = chrono::Utc::now().timestamp();
-            let mut validators_with_timestamp = validators_with_timestamp.write().await;
+            newer.push(staking_pool);
+            continue;
+        }
 
-            validators_with_timestamp.timestamp = timestamp;
+        let previous_min_delegation =
+            validators_with_timestamp.min_delegation_of(&staking_pool, min_delegation);
+        let (added, removed) = index::diff(
             validators_with_timestamp
                 .validator_staking_pools
-                .insert(validator_account_id.clone(), validator_delegators);
-
-            let updated_delegators_with_timestamp =
-                DelegatorsWithTimestamp::from(&validators_with_timestamp.clone());
-            drop(validators_with_timestamp);
+                .get(&staking_pool)
+                .map(Arc::as_ref),
+            &validator_delegators,
+            |stake| stake.total() >= previous_min_delegation,
+            |stake| stake.total() >= min_delegation,
+        );
+        diffs.push((staking_pool, validator_delegators, added, removed));
+    }
 
-            *delegators_with_timestamp.write().await = updated_delegators_with_timestamp.clone();
+    let version = network_state
+        .change_log
+        .append_all(
+            diffs
+                .iter()
+                .map(|(staking_pool, _, added, removed)| Change {
+                    validator_account_id: staking_pool.to_string(),
+                    block_height,
+                    timestamp,
+                    added: added.iter().map(ToString::to_string).collect(),
+                    removed: removed.iter().map(ToString::to_string).collect(),
+                })
+                .collect(),
+        )
+        .await
+        .context("Failed to record changes of the resync")?;
+
+    let mut applied = Vec::new();
+    let mut diffs_to_apply = Vec::new();
+    for (staking_pool, validator_delegators, added, removed) in diffs {
+        validators_with_timestamp
+            .validator_staking_pools
+            .insert(staking_pool.clone(), Arc::new(validator_delegators));
+        let metadata = validators_with_timestamp
+            .validators_metadata
+            .entry(staking_pool.clone())
+            .or_default();
+        metadata.block_height = Some(block_height);
+        metadata.timestamp = Some(timestamp);
+        applied.push(staking_pool.clone());
+        diffs_to_apply.push((staking_pool, added, removed));
+    }
+    validators_with_timestamp.timestamp = timestamp;
+
+    // Same locking order as a refresh of a single staking pool.
+    let mut locked_delegators = network_state.delegators_state.write().await;
+    let staking_pools_metadata = validators_with_timestamp.validators_metadata.clone();
+    drop(validators_with_timestamp);
+
+    if !network_state.config.lookups_from_store {
+        for (staking_pool, added, removed) in &diffs_to_apply {
+            index::apply_diff(
+                &mut locked_delegators.delegator_staking_pools,
+                staking_pool,
+                added,
+                removed,
+            );
+        }
+    }
+    locked_delegators.timestamp = timestamp;
+    locked_delegators.version = Some(version);
+    locked_delegators.staking_pools_metadata = staking_pools_metadata;
+    drop(locked_delegators);
 
-            info!("Updated delegators for validator: {}", validator_account_id);
+    Ok((applied, newer))
+}
 
-            return Ok(());
+/// Removes a staking pool that no longer exists from both directions of the index, recording
+/// its visible delegators as removed. Returns them, or `None` if the staking pool is unknown
+/// or the eviction can't be recorded.
+pub async fn evict_staking_pool(
+    network_state: &NetworkState,
+    staking_pool: &Id,
+    block_height: u64,
+) -> Option<Vec<Id>> {
+    let min_delegation = network_state.config.min_delegation;
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let mut validators_with_timestamp = network_state.validators_state.write().await;
+    let min_delegation = validators_with_timestamp.min_delegation_of(staking_pool, min_delegation);
+    let validator_delegators = validators_with_timestamp
+        .validator_staking_pools
+        .remove(staking_pool)?;
+    let metadata = validators_with_timestamp
+        .validators_metadata
+        .remove(staking_pool);
+
+    let (_, removed) = index::diff(
+        Some(&validator_delegators),
+        &BTreeMap::new(),
+        |stake| stake.total() >= min_delegation,
+        |stake| stake.total() >= min_delegation,
+    );
+    // Kept until the eviction can be recorded.
+    let version = match network_state
+        .change_log
+        .append(
+            staking_pool,
+            block_height,
+            timestamp,
+            Vec::new(),
+            removed.iter().map(ToString::to_string).collect(),
+        )
+        .await
+    {
+        Ok(version) => version,
+        Err(e) => {
+            error!("Failed to record eviction of {}: {:#}", staking_pool, e);
+            validators_with_timestamp
+                .validator_staking_pools
+                .insert(staking_pool.clone(), validator_delegators);
+            if let Some(metadata) = metadata {
+                validators_with_timestamp
+                    .validators_metadata
+                    .insert(staking_pool.clone(), metadata);
+            }
+            return None;
         }
-
-        warn!(
-            "Failed to get delegators for validator_account_id: {}. Retrying...",
-            validator_account_id
+    };
+
+    let mut locked_delegators = network_state.delegators_state.write().await;
+    validators_with_timestamp.timestamp = timestamp;
+    drop(validators_with_timestamp);
+
+    if !network_state.config.lookups_from_store {
+        index::apply_diff(
+            &mut locked_delegators.delegator_staking_pools,
+            staking_pool,
+            &[],
+            &removed,
         );
-        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    }
+    locked_delegators.timestamp = timestamp;
+    locked_delegators.version = Some(version);
+    locked_delegators
+        .staking_pools_metadata
+        .remove(staking_pool);
+    drop(locked_delegators);
+
+    if let Err(e) = network_state.change_log.sync().await {
+        error!("Failed to sync eviction of {}: {:#}", staking_pool, e);
     }
 
-    color_eyre::eyre::bail!(
-        "Failed to get delegators for validator_account_id: {}",
-        validator_account_id
-    )
+    Some(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_network_state;
+
+    use serde_json::json;
+
+    fn staked(delegator: &str) -> BTreeMap<Id, DelegatorStake> {
+        BTreeMap::from([(
+            Id::from(delegator),
+            DelegatorStake {
+                staked_balance: 10,
+                unstaked_balance: 0,
+                can_withdraw: true,
+            },
+        )])
+    }
+
+    /// E.g. a lake backfilled from an old `follower_start_height` while the index is already
+    /// at a newer block.
+    #[tokio::test]
+    async fn a_refresh_at_an_older_block_leaves_the_index_and_change_log_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (network_state, _) =
+            test_network_state(dir.path(), "http://127.0.0.1:1", json!({})).await;
+        let staking_pool = Id::from("a.poolv1.near");
+
+        apply_refresh(
+            &network_state,
+            staking_pool.clone(),
+            200,
+            staked("alice.near"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(network_state.change_log.sync().await.unwrap(), 1);
+
+        apply_refresh(
+            &network_state,
+            staking_pool.clone(),
+            150,
+            staked("bob.near"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(network_state.change_log.sync().await.unwrap(), 1);
+
+        let validators_with_timestamp = network_state.validators_state.read().await;
+        assert_eq!(
+            *validators_with_timestamp.validator_staking_pools[&staking_pool],
+            staked("alice.near")
+        );
+        assert_eq!(
+            validators_with_timestamp.validators_metadata[&staking_pool].block_height,
+            Some(200)
+        );
+        drop(validators_with_timestamp);
+
+        let delegators_with_timestamp = network_state.delegators_state.read().await;
+        assert_eq!(delegators_with_timestamp.version, Some(1));
+        assert_eq!(
+            delegators_with_timestamp
+                .delegator_staking_pools
+                .keys()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["alice.near"]
+        );
+    }
 }
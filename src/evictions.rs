@@ -0,0 +1,378 @@
+use crate::delegators;
+use crate::interner::Id;
+use crate::network::NetworkState;
+
+use color_eyre::{eyre::Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// A staking pool removed from the index because it no longer exists.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Eviction {
+    pub staking_pool: String,
+    /// When the staking pool was first found missing.
+    pub missing_since: i64,
+    pub evicted_at: i64,
+    /// Final block the staking pool was found missing at for the last time.
+    pub block_height: u64,
+    /// Block the delegators of the staking pool were last read at.
+    pub last_block_height: Option<u64>,
+    /// Delegators the staking pool still listed, who were recorded as leaving it.
+    pub delegators: usize,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct EvictionsReport {
+    /// Staking pools found missing that are still in their grace period, with when they were
+    /// first found missing.
+    pub pending: BTreeMap<String, i64>,
+    /// Evicted staking pools, oldest first.
+    pub evicted: Vec<Eviction>,
+}
+
+struct EvictionsInner {
+    file: tokio::fs::File,
+    missing: BTreeMap<String, i64>,
+    evicted: Vec<Eviction>,
+}
+
+/// Staking pools that are missing from the pool factories, and the ones that were evicted
+/// for it, kept in an append-only NDJSON file for audit.
+///
+/// When the staking pools in their grace period were first found missing is kept next to it
+/// in a JSON file, rewritten whenever it changes, so that the grace period goes on across
+/// restarts.
+pub struct Evictions {
+    missing_path: PathBuf,
+    inner: Mutex<EvictionsInner>,
+}
+
+impl Evictions {
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        let missing_path = path.with_extension("pending.json");
+        let missing = match tokio::fs::read(&missing_path).await {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                warn!(
+                    "Starting the grace periods over, unreadable <{}>: {}",
+                    missing_path.display(),
+                    e
+                );
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read pending evictions <{}>",
+                        missing_path.display()
+                    )
+                })
+            }
+        };
+
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read evictions <{}>", path.display()))
+            }
+        };
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open evictions <{}>", path.display()))?;
+
+        // A line cut short by a crash is terminated so that new evictions start on a line of
+        // their own.
+        if !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n").await?;
+        }
+
+        let mut evicted = Vec::new();
+        for line in content.lines() {
+            match serde_json::from_str::<Eviction>(line) {
+                Ok(eviction) => evicted.push(eviction),
+                Err(e) => warn!(
+                    "Skipping unreadable eviction in <{}>: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        Ok(Self {
+            missing_path,
+            inner: Mutex::new(EvictionsInner {
+                file,
+                missing,
+                evicted,
+            }),
+        })
+    }
+
+    pub async fn report(&self) -> EvictionsReport {
+        let inner = self.inner.lock().await;
+
+        EvictionsReport {
+            pending: inner.missing.clone(),
+            evicted: inner.evicted.clone(),
+        }
+    }
+
+    /// Starts the grace period of the staking pools found missing, forgets the ones that
+    /// showed up again, and returns the missing ones whose grace period is over with when
+    /// they were first found missing. The staking pools whose check failed are left as they
+    /// were.
+    async fn track(
+        &self,
+        missing: &BTreeSet<Id>,
+        unchecked: &BTreeSet<Id>,
+        grace_period: i64,
+    ) -> Vec<(Id, i64)> {
+        let now = chrono::Utc::now().timestamp();
+        let mut inner = self.inner.lock().await;
+        let previous = inner.missing.clone();
+
+        inner.missing.retain(|staking_pool, _| {
+            missing.contains(staking_pool.as_str()) || unchecked.contains(staking_pool.as_str())
+        });
+
+        let due = missing
+            .iter()
+            .filter_map(|staking_pool| {
+                let missing_since = *inner.missing.entry(staking_pool.to_string()).or_insert(now);
+                (now - missing_since >= grace_period).then(|| (staking_pool.clone(), missing_since))
+            })
+            .collect();
+
+        if inner.missing != previous {
+            if let Err(e) = write_missing(&self.missing_path, &inner.missing).await {
+                error!("{:#}", e);
+            }
+        }
+
+        due
+    }
+
+    /// Remembers an eviction. It is kept in memory even if it could not be written, and the
+    /// staking pool leaves the pending evictions file even if the eviction could not be
+    /// appended, so that it does not come back as pending after a restart.
+    async fn record(&self, eviction: Eviction) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+
+        let mut line = serde_json::to_vec(&eviction)?;
+        line.push(b'\n');
+        inner.missing.remove(&eviction.staking_pool);
+        inner.evicted.push(eviction);
+
+        let appended = inner
+            .file
+            .write_all(&line)
+            .await
+            .context("Failed to append to the evictions");
+        let written = write_missing(&self.missing_path, &inner.missing).await;
+
+        appended.and(written)
+    }
+}
+
+/// Replaces the pending evictions file, through a temporary file renamed over it.
+async fn write_missing(path: &Path, missing: &BTreeMap<String, i64>) -> Result<()> {
+    let temporary_path = path.with_extension("json.tmp");
+    tokio::fs::write(&temporary_path, serde_json::to_vec(missing)?)
+        .await
+        .with_context(|| format!("Failed to write pending evictions <{}>", path.display()))?;
+    tokio::fs::rename(&temporary_path, path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to move pending evictions <{}> in place",
+                path.display()
+            )
+        })
+}
+
+/// Compares the known staking pools with the ones `discovered` from the pool factories at
+/// `block_height`, and evicts the ones that have been missing for longer than the grace
+/// period. Staking pools running one of the `staking_pool_code_hashes` contracts are not
+/// missing. Returns the evicted staking pools.
+pub async fn reconcile(
+    network_state: &NetworkState,
+    discovered: &BTreeSet<String>,
+    block_height: u64,
+) -> Vec<Id> {
+    let network = network_state.config.network;
+
+    // More likely a factory that could not be read properly than every staking pool gone.
+    if discovered.is_empty() {
+        return Vec::new();
+    }
+
+    let snapshot = network_state.snapshot();
+    let mut missing = BTreeSet::new();
+    let mut unchecked = BTreeSet::new();
+    for staking_pool in snapshot.validators.validator_staking_pools.keys() {
+        if discovered.contains(&**staking_pool) {
+            continue;
+        }
+
+        match network_state.runs_staking_pool_code(staking_pool).await {
+            Ok(true) => {}
+            Ok(false) => {
+                missing.insert(staking_pool.clone());
+            }
+            Err(e) => {
+                warn!("[{network}] Failed to check the contract of {staking_pool}: {e:#}");
+                unchecked.insert(staking_pool.clone());
+            }
+        }
+    }
+
+    let grace_period =
+        i64::try_from(network_state.config.eviction_grace_period).unwrap_or(i64::MAX);
+    let due = network_state
+        .evictions
+        .track(&missing, &unchecked, grace_period)
+        .await;
+
+    let mut evicted = Vec::new();
+    for (staking_pool, missing_since) in due {
+        let last_block_height = snapshot
+            .validators
+            .validators_metadata
+            .get(&staking_pool)
+            .and_then(|metadata| metadata.block_height);
+
+        let Some(removed) =
+            delegators::evict_staking_pool(network_state, &staking_pool, block_height).await
+        else {
+            continue;
+        };
+
+        warn!(
+            "[{network}] Evicted staking pool {staking_pool}, missing since {missing_since}, \
+             with {} delegators",
+            removed.len()
+        );
+
+        if let Err(e) = network_state
+            .evictions
+            .record(Eviction {
+                staking_pool: staking_pool.to_string(),
+                missing_since,
+                evicted_at: chrono::Utc::now().timestamp(),
+                block_height,
+                last_block_height,
+                delegators: removed.len(),
+            })
+            .await
+        {
+            error!("[{network}] Failed to record eviction of {staking_pool}: {e:#}");
+        }
+
+        evicted.push(staking_pool);
+    }
+
+    // The worker publishes the new snapshot and flushes the store.
+    if !evicted.is_empty() && network_state.tx.send(()).await.is_err() {
+        error!("[{network}] Failed to send message to the worker");
+    }
+
+    evicted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pools(pools: &[&str]) -> BTreeSet<Id> {
+        pools.iter().map(|pool| Id::from(*pool)).collect()
+    }
+
+    #[tokio::test]
+    async fn pending_and_recorded_evictions_are_kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("evictions.ndjson");
+
+        let evictions = Evictions::open(path.clone()).await.unwrap();
+        let due = evictions
+            .track(&pools(&["a.poolv1.near", "b.poolv1.near"]), &pools(&[]), 60)
+            .await;
+        assert!(due.is_empty());
+        let pending = evictions.report().await.pending;
+        drop(evictions);
+
+        let evictions = Evictions::open(path.clone()).await.unwrap();
+        assert_eq!(evictions.report().await.pending, pending);
+
+        // Past the grace period, the staking pools missing since then are due.
+        let due = evictions
+            .track(&pools(&["a.poolv1.near"]), &pools(&[]), 0)
+            .await;
+        assert_eq!(due, [(Id::from("a.poolv1.near"), pending["a.poolv1.near"])]);
+        assert_eq!(
+            evictions.report().await.pending.keys().collect::<Vec<_>>(),
+            ["a.poolv1.near"]
+        );
+
+        // Once evicted, the staking pool is no longer pending after a restart either.
+        evictions
+            .record(Eviction {
+                staking_pool: "a.poolv1.near".to_string(),
+                missing_since: pending["a.poolv1.near"],
+                evicted_at: pending["a.poolv1.near"],
+                block_height: 100,
+                last_block_height: Some(90),
+                delegators: 1,
+            })
+            .await
+            .unwrap();
+        drop(evictions);
+
+        let report = Evictions::open(path).await.unwrap().report().await;
+        assert!(report.pending.is_empty());
+        assert_eq!(
+            report
+                .evicted
+                .iter()
+                .map(|eviction| eviction.staking_pool.as_str())
+                .collect::<Vec<_>>(),
+            ["a.poolv1.near"]
+        );
+    }
+
+    #[tokio::test]
+    async fn staking_pools_that_could_not_be_checked_stay_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let evictions = Evictions::open(dir.path().join("evictions.ndjson"))
+            .await
+            .unwrap();
+
+        evictions
+            .track(&pools(&["a.poolv1.near"]), &pools(&[]), 60)
+            .await;
+        let pending = evictions.report().await.pending;
+
+        // Neither forgotten nor evicted, even with the grace period over.
+        let due = evictions
+            .track(&pools(&[]), &pools(&["a.poolv1.near"]), 0)
+            .await;
+        assert!(due.is_empty());
+        assert_eq!(evictions.report().await.pending, pending);
+
+        // A staking pool that could not be checked is not found missing either.
+        let due = evictions
+            .track(&pools(&[]), &pools(&["b.poolv1.near"]), 0)
+            .await;
+        assert!(due.is_empty());
+        assert!(evictions.report().await.pending.is_empty());
+    }
+}
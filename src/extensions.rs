@@ -25,7 +25,9 @@ pub impl near_primitives::views::CallResult {
     }
 }
 
-#[derive(serde::Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(serde::Deserialize)]
 pub struct Delegator {
     pub account_id: near_primitives::types::AccountId,
+    #[serde(flatten)]
+    pub stake: crate::delegators::DelegatorStake,
 }
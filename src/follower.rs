@@ -0,0 +1,691 @@
+use crate::network::NetworkState;
+use crate::rpc::RpcPool;
+
+use color_eyre::{eyre::Context, Result};
+use near_jsonrpc_client::errors::{JsonRpcError, JsonRpcServerError};
+use near_jsonrpc_client::methods::block::RpcBlockError;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{BlockId, BlockReference, Finality, TransactionOrReceiptId};
+use near_primitives::views::{ActionView, ExecutionStatusView, ReceiptEnumView};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Staking pool methods that change the positions of delegators.
+pub const STAKING_METHODS: &[&str] = &[
+    "deposit",
+    "deposit_and_stake",
+    "stake",
+    "stake_all",
+    "unstake",
+    "unstake_all",
+    "withdraw",
+    "withdraw_all",
+];
+/// Blocks processed before the worker is notified.
+const MAX_BLOCKS_PER_BATCH: u64 = 100;
+
+/// A function call that executed successfully.
+#[derive(Debug, Clone)]
+pub struct FunctionCall {
+    pub receiver_id: String,
+    pub method_name: String,
+    /// Block the call was executed in, which may follow the one it was included in.
+    pub block_height: u64,
+}
+
+/// Where the follower reads blocks from.
+#[rocket::async_trait]
+pub trait BlockSource: Send + Sync {
+    /// Height of the latest final block. `after` is the last processed block, sources that can
+    /// only list blocks forward may stop looking a limited number of blocks after it, and
+    /// refuse to look without it.
+    async fn final_height(&self, after: Option<u64>) -> Result<u64>;
+
+    /// Accounts whose contract was called in the block at `height`, or `None` if no block was
+    /// produced at that height. Sources that can't tell which calls succeeded without looking
+    /// them up one by one may list accounts whose calls failed.
+    async fn touched_accounts(&self, height: u64) -> Result<Option<BTreeSet<String>>>;
+
+    /// Staking calls to `account_id` in the block at `height` that executed successfully.
+    async fn staking_calls(&self, height: u64, account_id: &str) -> Result<Vec<FunctionCall>>;
+}
+
+fn method_names_of(actions: &[ActionView]) -> Vec<String> {
+    actions
+        .iter()
+        .filter_map(|action| match action {
+            ActionView::FunctionCall { method_name, .. } => Some(method_name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A transaction or receipt included in a block with the methods it calls.
+#[derive(Debug, Clone)]
+struct IncludedCall {
+    id: TransactionOrReceiptId,
+    receiver_id: String,
+    method_names: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct IncludedBlock {
+    hash: CryptoHash,
+    calls: Vec<IncludedCall>,
+}
+
+/// Reads blocks and their chunks with the `block` and `chunk` RPC methods.
+///
+/// Chunks hold no execution outcomes, so every staking call is looked up with the
+/// `light_client_proof` RPC method to find out whether it succeeded and in which block.
+pub struct RpcBlockSource {
+    rpc_pool: RpcPool,
+    /// Calls of the block read last, which the follower asks about once per account.
+    last_block: Mutex<Option<(u64, Option<IncludedBlock>)>>,
+}
+
+impl RpcBlockSource {
+    pub fn new(rpc_pool: RpcPool) -> Self {
+        Self {
+            rpc_pool,
+            last_block: Mutex::default(),
+        }
+    }
+
+    async fn block(
+        &self,
+        block_reference: BlockReference,
+    ) -> Result<near_primitives::views::BlockView, JsonRpcError<RpcBlockError>> {
+        self.rpc_pool
+            .call(near_jsonrpc_client::methods::block::RpcBlockRequest { block_reference })
+            .await
+    }
+
+    async fn chunk(&self, chunk_hash: CryptoHash) -> Result<near_primitives::views::ChunkView> {
+        self.rpc_pool
+            .call(near_jsonrpc_client::methods::chunk::RpcChunkRequest {
+                chunk_reference:
+                    near_jsonrpc_primitives::types::chunks::ChunkReference::ChunkHash {
+                        chunk_id: chunk_hash,
+                    },
+            })
+            .await
+            .with_context(|| format!("Failed to fetch chunk {chunk_hash}"))
+    }
+
+    async fn included_block(&self, height: u64) -> Result<Option<IncludedBlock>> {
+        let mut last_block = self.last_block.lock().await;
+        if let Some((last_height, block)) = &*last_block {
+            if *last_height == height {
+                return Ok(block.clone());
+            }
+        }
+
+        let block = self.read_included_block(height).await?;
+        *last_block = Some((height, block.clone()));
+        Ok(block)
+    }
+
+    async fn read_included_block(&self, height: u64) -> Result<Option<IncludedBlock>> {
+        let block = match self.block(BlockId::Height(height).into()).await {
+            Ok(block) => block,
+            Err(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcBlockError::UnknownBlock { .. },
+            ))) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to fetch block {height}")),
+        };
+
+        // Chunks that were not produced for this block are repeated from older ones.
+        let chunks = futures::future::try_join_all(
+            block
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.height_included == height)
+                .map(|chunk| self.chunk(chunk.chunk_hash)),
+        )
+        .await?;
+
+        let mut calls = Vec::new();
+        for chunk in chunks {
+            for transaction in chunk.transactions {
+                calls.push(IncludedCall {
+                    method_names: method_names_of(&transaction.actions),
+                    receiver_id: transaction.receiver_id.to_string(),
+                    id: TransactionOrReceiptId::Transaction {
+                        transaction_hash: transaction.hash,
+                        sender_id: transaction.signer_id,
+                    },
+                });
+            }
+            for receipt in chunk.receipts {
+                if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt {
+                    calls.push(IncludedCall {
+                        method_names: method_names_of(actions),
+                        receiver_id: receipt.receiver_id.to_string(),
+                        id: TransactionOrReceiptId::Receipt {
+                            receipt_id: receipt.receipt_id,
+                            receiver_id: receipt.receiver_id,
+                        },
+                    });
+                }
+            }
+        }
+        calls.retain(|call| !call.method_names.is_empty());
+
+        Ok(Some(IncludedBlock {
+            hash: block.header.hash,
+            calls,
+        }))
+    }
+
+    async fn outcome(
+        &self,
+        id: TransactionOrReceiptId,
+        light_client_head: CryptoHash,
+    ) -> Result<near_primitives::views::ExecutionOutcomeWithIdView> {
+        let description = match &id {
+            TransactionOrReceiptId::Transaction {
+                transaction_hash, ..
+            } => format!("transaction {transaction_hash}"),
+            TransactionOrReceiptId::Receipt { receipt_id, .. } => format!("receipt {receipt_id}"),
+        };
+
+        Ok(self
+            .rpc_pool
+            .call(
+                near_jsonrpc_client::methods::light_client_proof::RpcLightClientExecutionProofRequest {
+                    id,
+                    light_client_head,
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to fetch the outcome of {description}"))?
+            .outcome_proof)
+    }
+
+    /// Outcome of the receipt that executes `call`, following a transaction to the receipt it
+    /// was converted to. `None` if the transaction failed before that.
+    async fn receipt_outcome(
+        &self,
+        call: &IncludedCall,
+        light_client_head: CryptoHash,
+    ) -> Result<Option<near_primitives::views::ExecutionOutcomeWithIdView>> {
+        let outcome = self.outcome(call.id.clone(), light_client_head).await?;
+        let TransactionOrReceiptId::Transaction { .. } = call.id else {
+            return Ok(Some(outcome));
+        };
+
+        match outcome.outcome.status {
+            ExecutionStatusView::SuccessReceiptId(receipt_id) => {
+                let receipt = TransactionOrReceiptId::Receipt {
+                    receipt_id,
+                    receiver_id: call.receiver_id.parse()?,
+                };
+                self.outcome(receipt, light_client_head).await.map(Some)
+            }
+            ExecutionStatusView::Failure(_) => Ok(None),
+            status => color_eyre::eyre::bail!(
+                "Unexpected outcome {status:?} of transaction {}",
+                outcome.id
+            ),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl BlockSource for RpcBlockSource {
+    async fn final_height(&self, _after: Option<u64>) -> Result<u64> {
+        let block = self
+            .block(Finality::Final.into())
+            .await
+            .context("Failed to fetch the final block")?;
+
+        Ok(block.header.height)
+    }
+
+    async fn touched_accounts(&self, height: u64) -> Result<Option<BTreeSet<String>>> {
+        Ok(self.included_block(height).await?.map(|block| {
+            block
+                .calls
+                .into_iter()
+                .map(|call| call.receiver_id)
+                .collect()
+        }))
+    }
+
+    async fn staking_calls(&self, height: u64, account_id: &str) -> Result<Vec<FunctionCall>> {
+        let Some(block) = self.included_block(height).await? else {
+            return Ok(Vec::new());
+        };
+        let included_calls = block
+            .calls
+            .iter()
+            .filter(|call| {
+                call.receiver_id == account_id
+                    && call
+                        .method_names
+                        .iter()
+                        .any(|method_name| STAKING_METHODS.contains(&method_name.as_str()))
+            })
+            .collect::<Vec<_>>();
+        if included_calls.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Outcomes are proven against a final block, which every outcome of the processed
+        // blocks precedes unless the receipt was delayed, then the block is read again.
+        let light_client_head = self
+            .block(Finality::Final.into())
+            .await
+            .context("Failed to fetch the final block")?
+            .header
+            .hash;
+
+        let mut calls = Vec::new();
+        for included_call in included_calls {
+            let Some(outcome) = self
+                .receipt_outcome(included_call, light_client_head)
+                .await?
+            else {
+                continue;
+            };
+
+            match outcome.outcome.status {
+                ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_) => {
+                }
+                ExecutionStatusView::Failure(_) => continue,
+                ExecutionStatusView::Unknown => {
+                    color_eyre::eyre::bail!("Receipt {} has not been executed yet", outcome.id)
+                }
+            }
+
+            let block_height = if outcome.block_hash == block.hash {
+                height
+            } else {
+                self.block(BlockId::Hash(outcome.block_hash).into())
+                    .await
+                    .with_context(|| format!("Failed to fetch block {}", outcome.block_hash))?
+                    .header
+                    .height
+            };
+
+            calls.extend(
+                included_call
+                    .method_names
+                    .iter()
+                    .filter(|method_name| STAKING_METHODS.contains(&method_name.as_str()))
+                    .map(|method_name| FunctionCall {
+                        receiver_id: included_call.receiver_id.clone(),
+                        method_name: method_name.clone(),
+                        block_height,
+                    }),
+            );
+        }
+
+        Ok(calls)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Cursor {
+    /// Last processed block height.
+    block_height: u64,
+}
+
+async fn load_cursor(path: &Path) -> Result<Option<u64>> {
+    match tokio::fs::read(path).await {
+        Ok(content) => Ok(Some(
+            serde_json::from_slice::<Cursor>(&content)
+                .context("Failed to parse follower cursor")?
+                .block_height,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to read follower cursor"),
+    }
+}
+
+async fn save_cursor(path: &Path, block_height: u64) -> Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    tokio::fs::write(
+        &temporary_path,
+        serde_json::to_vec(&Cursor { block_height })?,
+    )
+    .await?;
+    tokio::fs::rename(&temporary_path, path).await?;
+
+    Ok(())
+}
+
+/// Staking calls that executed successfully on staking pools in the block at `height`.
+async fn staking_calls(
+    network_state: &NetworkState,
+    source: &dyn BlockSource,
+    height: u64,
+) -> Result<Vec<FunctionCall>> {
+    let mut calls = Vec::new();
+
+    for account_id in source.touched_accounts(height).await?.into_iter().flatten() {
+        // The receipts are only looked up for staking pools. A failed check fails the block,
+        // which is read again, so that no call is lost.
+        if !network_state
+            .verify_staking_pool(&account_id)
+            .await
+            .with_context(|| format!("Failed to check <{account_id}>"))?
+        {
+            continue;
+        }
+
+        calls.extend(source.staking_calls(height, &account_id).await?);
+    }
+
+    Ok(calls)
+}
+
+/// Forgets the staking pools queued by the follower that the worker has processed since: they
+/// were refreshed at the last height they were queued at or later, or failed to be, in which
+/// case they are left to the next full refresh like any failed refresh.
+///
+/// `pending` holds the first block a call of each staking pool was included in and the last
+/// height it was queued at.
+async fn settle(network_state: &NetworkState, pending: &mut BTreeMap<String, (u64, u64)>) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let validators_with_timestamp = network_state.validators_state.read().await;
+    pending.retain(|staking_pool, (_, queued_height)| {
+        let Some(metadata) = validators_with_timestamp
+            .validators_metadata
+            .get(staking_pool.as_str())
+        else {
+            return true;
+        };
+        let refreshed = metadata
+            .block_height
+            .is_some_and(|block_height| block_height >= *queued_height);
+        let failed = metadata
+            .last_failure
+            .as_ref()
+            .is_some_and(|failure| failure.block_height >= *queued_height);
+
+        !refreshed && !failed
+    });
+}
+
+/// Follows the chain from the saved cursor (or the configured start height, or the latest
+/// final block on the first run) and queues every staking pool that successfully executed a
+/// staking call to be refreshed at the block it was executed in.
+///
+/// The queue is only kept in memory, so the saved cursor stays before the first block with a
+/// call the worker has not processed yet. Those blocks are read again after a restart.
+pub async fn run(network_state: NetworkState, source: Arc<dyn BlockSource>, cursor_path: PathBuf) {
+    let network = network_state.config.network;
+    let poll_interval = Duration::from_secs(network_state.config.follower_poll_interval);
+
+    let mut cursor = match load_cursor(&cursor_path).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            error!("[{network}] Follower stopped: {:#}", e);
+            return;
+        }
+    };
+    let start_height = network_state.config.follower_start_height;
+    match (cursor, start_height) {
+        (Some(cursor), _) => info!("[{network}] Following the chain after block {cursor}"),
+        (None, Some(start_height)) => {
+            info!("[{network}] Following the chain from block {start_height}");
+        }
+        (None, None) => info!("[{network}] Following the chain from the final block"),
+    }
+
+    let mut saved_cursor = cursor;
+    let mut pending = BTreeMap::new();
+
+    loop {
+        settle(&network_state, &mut pending).await;
+        let processed = cursor.map(|cursor| {
+            pending
+                .values()
+                .map(|(included_height, _)| included_height - 1)
+                .fold(cursor, u64::min)
+        });
+        if let Some(processed) = processed.filter(|_| processed != saved_cursor) {
+            match save_cursor(&cursor_path, processed).await {
+                Ok(()) => saved_cursor = Some(processed),
+                Err(e) => error!("[{network}] Failed to save follower cursor: {:#}", e),
+            }
+        }
+
+        // Before the first run, the block preceding the start height counts as processed.
+        let after =
+            cursor.or_else(|| start_height.map(|start_height| start_height.saturating_sub(1)));
+        let final_height = match source.final_height(after).await {
+            Ok(final_height) => final_height,
+            Err(e) => {
+                error!(
+                    "[{network}] Follower failed to get the final block: {:#}",
+                    e
+                );
+                tokio::time::sleep(poll_interval).await;
+                continue;
+            }
+        };
+
+        let start =
+            cursor.map_or_else(|| start_height.unwrap_or(final_height), |cursor| cursor + 1);
+        if start > final_height {
+            tokio::time::sleep(poll_interval).await;
+            continue;
+        }
+        let end = final_height.min(start + MAX_BLOCKS_PER_BATCH - 1);
+
+        let mut found = false;
+        for height in start..=end {
+            let calls = match staking_calls(&network_state, source.as_ref(), height).await {
+                Ok(calls) => calls,
+                Err(e) => {
+                    error!(
+                        "[{network}] Follower failed to read block {height}: {:#}",
+                        e
+                    );
+                    tokio::time::sleep(poll_interval).await;
+                    break;
+                }
+            };
+
+            for call in calls {
+                info!(
+                    "[{network}] Block {height}: {} on {}",
+                    call.method_name, call.receiver_id
+                );
+                pending
+                    .entry(call.receiver_id.clone())
+                    .and_modify(|(_, queued_height): &mut (u64, u64)| {
+                        *queued_height = (*queued_height).max(call.block_height);
+                    })
+                    .or_insert((height, call.block_height));
+                network_state
+                    .enqueue(call.receiver_id, call.block_height)
+                    .await;
+                found = true;
+            }
+
+            cursor = Some(height);
+        }
+
+        if found && network_state.tx.send(()).await.is_err() {
+            error!("[{network}] Failed to send message to the worker");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delegators::{ValidatorFailure, ValidatorMetadata};
+    use crate::interner::Id;
+    use crate::network::test_network_state;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Serves the JSON-RPC exchanges recorded in `fixture`, an array of
+    /// `{"method", "params", "result" | "error"}` objects, and returns the server's URL.
+    async fn serve_recorded(fixture: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(fixture);
+        let exchanges: Arc<Vec<Value>> =
+            Arc::new(serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(stream, exchanges.clone()));
+            }
+        });
+
+        url
+    }
+
+    async fn serve_connection(stream: tokio::net::TcpStream, exchanges: Arc<Vec<Value>>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+
+            let exchange = exchanges
+                .iter()
+                .find(|exchange| {
+                    exchange["method"] == request["method"]
+                        && exchange["params"] == request["params"]
+                })
+                .unwrap_or_else(|| panic!("No recorded response to {request}"));
+            let mut response = json!({ "jsonrpc": "2.0", "id": request["id"] });
+            for key in ["result", "error"] {
+                if let Some(value) = exchange.get(key) {
+                    response[key] = value.clone();
+                }
+            }
+
+            let response = response.to_string();
+            writer
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                         content-length: {}\r\n\r\n{response}",
+                        response.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Block 101 holds a successful `deposit_and_stake` on a.poolv1.near, another one on
+    /// e.poolv1.near whose receipt was only executed in block 103, a call to a non-staking
+    /// account and a failed `stake` on b.poolv1.near. Block 102 is missing, and block 103
+    /// holds a `ping` and an `unstake` on d.poolv1.near next to a chunk repeated from block
+    /// 101, which is not read again. The cursor only moves past block 100 once the worker has
+    /// processed every queued staking pool.
+    #[tokio::test]
+    async fn queues_staking_pools_changed_by_successful_staking_calls() {
+        let rpc_url = serve_recorded("tests/fixtures/follower/rpc.json").await;
+        let dir = tempfile::tempdir().unwrap();
+        let (network_state, mut rx) = test_network_state(
+            dir.path(),
+            &rpc_url,
+            json!({ "follower_start_height": 101 }),
+        )
+        .await;
+        network_state.set_discovered_pools(
+            ["a", "b", "d", "e"]
+                .map(|pool| format!("{pool}.poolv1.near"))
+                .into(),
+        );
+
+        let cursor_path = dir.path().join("follower.json");
+        let follower = tokio::spawn(run(
+            network_state.clone(),
+            Arc::new(RpcBlockSource::new(network_state.lookup_rpc_pool.clone())),
+            cursor_path.clone(),
+        ));
+
+        let wait_for_cursor = |expected| {
+            let cursor_path = cursor_path.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(10), async {
+                    while load_cursor(&cursor_path).await.unwrap() != Some(expected) {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .unwrap_or_else(|_| panic!("the cursor did not reach block {expected}"));
+            }
+        };
+
+        rx.recv().await;
+        // Nothing was refreshed yet, so block 101 has to be read again after a restart.
+        wait_for_cursor(100).await;
+        assert_eq!(
+            *network_state.validators_to_process.read().await,
+            BTreeMap::from([
+                ("a.poolv1.near".to_string(), 101),
+                ("d.poolv1.near".to_string(), 103),
+                ("e.poolv1.near".to_string(), 103),
+            ])
+        );
+
+        // The worker refreshes a.poolv1.near and d.poolv1.near, and fails to refresh
+        // e.poolv1.near.
+        {
+            let mut validators_with_timestamp = network_state.validators_state.write().await;
+            for (staking_pool, block_height) in [("a.poolv1.near", 101), ("d.poolv1.near", 103)] {
+                validators_with_timestamp.validators_metadata.insert(
+                    Id::from(staking_pool),
+                    ValidatorMetadata {
+                        block_height: Some(block_height),
+                        ..ValidatorMetadata::default()
+                    },
+                );
+            }
+            validators_with_timestamp.validators_metadata.insert(
+                Id::from("e.poolv1.near"),
+                ValidatorMetadata {
+                    last_failure: Some(ValidatorFailure {
+                        timestamp: 0,
+                        block_height: 103,
+                        error: "unavailable".to_string(),
+                    }),
+                    ..ValidatorMetadata::default()
+                },
+            );
+        }
+        wait_for_cursor(103).await;
+        follower.abort();
+    }
+}
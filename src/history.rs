@@ -0,0 +1,170 @@
+use crate::delegators::ValidatorsWithTimestamp;
+
+use color_eyre::{eyre::Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+/// Point of the chain history a query refers to.
+#[derive(Debug, Clone, Copy)]
+pub enum PointInTime {
+    BlockHeight(u64),
+    /// Unix timestamp in seconds.
+    Timestamp(i64),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SnapshotInfo {
+    timestamp: i64,
+}
+
+/// Immutable snapshots of the index, one JSON file per snapshot named
+/// `<block_height>-<timestamp>.json`.
+pub struct History {
+    dir: PathBuf,
+    retention: Option<usize>,
+    snapshots: RwLock<BTreeMap<u64, SnapshotInfo>>,
+    /// Most recently read snapshot, as point-in-time queries tend to hit the same one.
+    last_loaded: Mutex<Option<(u64, Arc<ValidatorsWithTimestamp>)>>,
+}
+
+fn parse_snapshot_file_name(path: &Path) -> Option<(u64, SnapshotInfo)> {
+    let (block_height, timestamp) = path
+        .file_name()?
+        .to_str()?
+        .strip_suffix(".json")?
+        .split_once('-')?;
+
+    Some((
+        block_height.parse().ok()?,
+        SnapshotInfo {
+            timestamp: timestamp.parse().ok()?,
+        },
+    ))
+}
+
+impl History {
+    pub async fn open(dir: PathBuf, retention: Option<usize>) -> Result<Self> {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create history directory <{}>", dir.display()))?;
+
+        let mut snapshots = BTreeMap::new();
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .context("Failed to read history directory")?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some((block_height, info)) = parse_snapshot_file_name(&entry.path()) {
+                snapshots.insert(block_height, info);
+            }
+        }
+
+        Ok(Self {
+            dir,
+            retention,
+            snapshots: RwLock::new(snapshots),
+            last_loaded: Mutex::new(None),
+        })
+    }
+
+    fn snapshot_path(&self, block_height: u64, info: SnapshotInfo) -> PathBuf {
+        self.dir
+            .join(format!("{block_height}-{}.json", info.timestamp))
+    }
+
+    pub async fn len(&self) -> usize {
+        self.snapshots.read().await.len()
+    }
+
+    /// Persists the current index as a snapshot at the highest block height any staking pool
+    /// was read at, and returns that height. Does nothing if there already is a snapshot at
+    /// that height.
+    pub async fn take_snapshot(
+        &self,
+        validators_with_timestamp: &ValidatorsWithTimestamp,
+    ) -> Result<Option<u64>> {
+        let Some(block_height) = validators_with_timestamp
+            .validators_metadata
+            .values()
+            .filter_map(|metadata| metadata.block_height)
+            .max()
+        else {
+            return Ok(None);
+        };
+        if self.snapshots.read().await.contains_key(&block_height) {
+            return Ok(None);
+        }
+
+        let info = SnapshotInfo {
+            timestamp: validators_with_timestamp.timestamp,
+        };
+        let content = serde_json::to_vec(validators_with_timestamp)?;
+
+        let path = self.snapshot_path(block_height, info);
+        let temporary_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary_path, content)
+            .await
+            .context("Failed to write snapshot")?;
+        tokio::fs::rename(&temporary_path, &path)
+            .await
+            .context("Failed to move snapshot in place")?;
+
+        let mut snapshots = self.snapshots.write().await;
+        snapshots.insert(block_height, info);
+
+        while let Some(retention) = self
+            .retention
+            .filter(|retention| snapshots.len() > *retention)
+        {
+            let Some((oldest_block_height, oldest_info)) = snapshots.pop_first() else {
+                break;
+            };
+            if let Err(e) =
+                tokio::fs::remove_file(self.snapshot_path(oldest_block_height, oldest_info)).await
+            {
+                warn!(
+                    "Failed to remove snapshot at block {} (keeping {}): {}",
+                    oldest_block_height, retention, e
+                );
+            }
+        }
+
+        Ok(Some(block_height))
+    }
+
+    /// The latest snapshot taken at or before `point`, or `None` if there is none.
+    pub async fn load(&self, point: PointInTime) -> Result<Option<Arc<ValidatorsWithTimestamp>>> {
+        let snapshots = self.snapshots.read().await;
+        let found = match point {
+            PointInTime::BlockHeight(block_height) => snapshots.range(..=block_height).next_back(),
+            PointInTime::Timestamp(timestamp) => snapshots
+                .iter()
+                .rev()
+                .find(|(_, info)| info.timestamp <= timestamp),
+        };
+        let Some((&block_height, &info)) = found else {
+            return Ok(None);
+        };
+        drop(snapshots);
+
+        if let Some((loaded_block_height, validators)) = &*self.last_loaded.lock().await {
+            if *loaded_block_height == block_height {
+                return Ok(Some(validators.clone()));
+            }
+        }
+
+        // Read without holding the cache, so that requests for other snapshots don't wait for
+        // it. Concurrent requests for the same one may read it more than once.
+        let content = tokio::fs::read(self.snapshot_path(block_height, info))
+            .await
+            .context("Failed to read snapshot")?;
+        let validators = Arc::new(
+            serde_json::from_slice::<ValidatorsWithTimestamp>(&content)
+                .context("Failed to parse snapshot")?,
+        );
+        *self.last_loaded.lock().await = Some((block_height, validators.clone()));
+
+        Ok(Some(validators))
+    }
+}
@@ -0,0 +1,174 @@
+//! Maintenance of the two directions of the delegation index: staking pool -> delegators (with
+//! their positions) and delegator -> staking pools.
+//!
+//! Kept free of the rest of the crate so that it can be benchmarked on its own.
+
+use crate::interner::Id;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Staking pool -> delegator -> position. The positions of a staking pool are replaced as a
+/// whole, so copies of the index share those of the staking pools that did not change.
+pub type ByStakingPool<V> = BTreeMap<Id, Arc<BTreeMap<Id, V>>>;
+/// Delegator -> staking pools. A persistent map, so that copies of the index share everything
+/// but the delegators changed since.
+pub type ByDelegator = im::OrdMap<Id, BTreeSet<Id>>;
+
+/// Builds the delegator -> staking pools direction from scratch, leaving out the positions for
+/// which `is_visible` is false given their staking pool.
+pub fn build_by_delegator<V>(
+    by_staking_pool: &ByStakingPool<V>,
+    is_visible: impl Fn(&Id, &V) -> bool,
+) -> ByDelegator {
+    let mut by_delegator = ByDelegator::new();
+
+    for (staking_pool, delegators) in by_staking_pool {
+        for (delegator, _) in delegators
+            .iter()
+            .filter(|(_, position)| is_visible(staking_pool, position))
+        {
+            by_delegator
+                .entry(delegator.clone())
+                .or_default()
+                .insert(staking_pool.clone());
+        }
+    }
+
+    by_delegator
+}
+
+/// Delegators that joined and left between two sets of positions of a staking pool, counting
+/// only the positions for which `was_visible` and `is_visible` are true respectively. Both maps
+/// are walked once side by side.
+pub fn diff<V>(
+    previous: Option<&BTreeMap<Id, V>>,
+    current: &BTreeMap<Id, V>,
+    was_visible: impl Fn(&V) -> bool,
+    is_visible: impl Fn(&V) -> bool,
+) -> (Vec<Id>, Vec<Id>) {
+    let mut previous = previous
+        .into_iter()
+        .flatten()
+        .filter(|(_, position)| was_visible(position))
+        .map(|(delegator, _)| delegator)
+        .peekable();
+    let mut current = current
+        .iter()
+        .filter(|(_, position)| is_visible(position))
+        .map(|(delegator, _)| delegator)
+        .peekable();
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    loop {
+        match (previous.peek(), current.peek()) {
+            (None, None) => break,
+            (Some(_), None) => removed.extend(previous.by_ref().cloned()),
+            (None, Some(_)) => added.extend(current.by_ref().cloned()),
+            (Some(left), Some(right)) => match left.cmp(right) {
+                Ordering::Less => removed.extend(previous.next().cloned()),
+                Ordering::Greater => added.extend(current.next().cloned()),
+                Ordering::Equal => {
+                    previous.next();
+                    current.next();
+                }
+            },
+        }
+    }
+
+    (added, removed)
+}
+
+/// Applies the delegators that joined and left `staking_pool` to the delegator -> staking
+/// pools direction. Delegators left without any staking pool are dropped.
+pub fn apply_diff(by_delegator: &mut ByDelegator, staking_pool: &Id, added: &[Id], removed: &[Id]) {
+    for delegator in added {
+        by_delegator
+            .entry(delegator.clone())
+            .or_default()
+            .insert(staking_pool.clone());
+    }
+
+    for delegator in removed {
+        if let Some(staking_pools) = by_delegator.get_mut(delegator) {
+            staking_pools.remove(staking_pool);
+            if staking_pools.is_empty() {
+                by_delegator.remove(delegator);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<Id> {
+        ids.iter().map(|id| Id::from(*id)).collect()
+    }
+
+    fn positions(positions: &[(&str, u128)]) -> BTreeMap<Id, u128> {
+        positions
+            .iter()
+            .map(|(delegator, stake)| (Id::from(*delegator), *stake))
+            .collect()
+    }
+
+    #[test]
+    fn diff_counts_only_visible_positions() {
+        let previous = positions(&[("a", 10), ("b", 10), ("c", 1), ("d", 10)]);
+        let current = positions(&[("b", 10), ("c", 10), ("d", 1), ("e", 10)]);
+        let visible = |stake: &u128| *stake >= 5;
+
+        let (added, removed) = diff(Some(&previous), &current, visible, visible);
+        assert_eq!(added, ids(&["c", "e"]));
+        assert_eq!(removed, ids(&["a", "d"]));
+
+        // A staking pool seen for the first time only adds delegators.
+        let (added, removed) = diff(None, &current, visible, visible);
+        assert_eq!(added, ids(&["b", "c", "e"]));
+        assert!(removed.is_empty());
+
+        // Lowering the threshold shows the dust positions that were already there.
+        let (added, removed) = diff(Some(&previous), &previous, visible, |_| true);
+        assert_eq!(added, ids(&["c"]));
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn apply_diff_keeps_both_directions_in_line() {
+        let staking_pool = Id::from("a.poolv1.near");
+        let other_pool = Id::from("b.poolv1.near");
+        let mut by_staking_pool = ByStakingPool::from([
+            (
+                staking_pool.clone(),
+                Arc::new(positions(&[("a", 10), ("b", 10)])),
+            ),
+            (other_pool.clone(), Arc::new(positions(&[("b", 10)]))),
+        ]);
+        let mut by_delegator = build_by_delegator(&by_staking_pool, |_, _| true);
+
+        let current = positions(&[("b", 10), ("c", 10)]);
+        let (added, removed) = diff(
+            by_staking_pool.get(&staking_pool).map(Arc::as_ref),
+            &current,
+            |_| true,
+            |_| true,
+        );
+        apply_diff(&mut by_delegator, &staking_pool, &added, &removed);
+        by_staking_pool.insert(staking_pool.clone(), Arc::new(current));
+
+        assert_eq!(
+            by_delegator,
+            build_by_delegator(&by_staking_pool, |_, _| true)
+        );
+        // A delegator left without any staking pool is dropped.
+        assert!(!by_delegator.contains_key("a"));
+        assert_eq!(
+            by_delegator[&Id::from("b")],
+            BTreeSet::from([staking_pool, other_pool])
+        );
+    }
+}
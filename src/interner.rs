@@ -0,0 +1,84 @@
+//! Account ids shared between both directions of the index and every snapshot of it, so that
+//! each id is held in memory once however many staking pools it delegates to.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Interned account id. Cloning it only bumps a reference count.
+pub type Id = Arc<str>;
+
+/// Table of the account ids in use.
+#[derive(Default)]
+pub struct Interner {
+    ids: Mutex<HashSet<Id>>,
+}
+
+/// Memory taken by the interned account ids.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InternerStats {
+    /// Distinct account ids.
+    pub ids: usize,
+    /// Bytes of the distinct account ids.
+    pub bytes: usize,
+    /// Bytes the account ids would take if every reference held its own copy.
+    pub uninterned_bytes: usize,
+}
+
+impl Interner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<Id>> {
+        self.ids
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The shared copy of `account_id`, added to the table if needed.
+    pub fn intern(&self, account_id: &str) -> Id {
+        get_or_insert(&mut self.lock(), account_id)
+    }
+
+    /// Same as [`Interner::intern`] for the keys of a map, taking the lock once.
+    pub fn intern_keys<K: AsRef<str>, V>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> BTreeMap<Id, V> {
+        let mut ids = self.lock();
+        entries
+            .into_iter()
+            .map(|(account_id, value)| (get_or_insert(&mut ids, account_id.as_ref()), value))
+            .collect()
+    }
+
+    /// Forgets the account ids that are only referenced by the table.
+    pub fn purge(&self) -> usize {
+        let mut ids = self.lock();
+        let before = ids.len();
+        ids.retain(|id| Arc::strong_count(id) > 1);
+        before - ids.len()
+    }
+
+    pub fn stats(&self) -> InternerStats {
+        let ids = self.lock();
+        ids.iter().fold(
+            InternerStats {
+                ids: ids.len(),
+                ..InternerStats::default()
+            },
+            |mut stats, id| {
+                stats.bytes += id.len();
+                // The table's own reference is left out.
+                stats.uninterned_bytes += id.len() * (Arc::strong_count(id) - 1);
+                stats
+            },
+        )
+    }
+}
+
+fn get_or_insert(ids: &mut HashSet<Id>, account_id: &str) -> Id {
+    if let Some(id) = ids.get(account_id) {
+        return Arc::clone(id);
+    }
+
+    let id: Id = Arc::from(account_id);
+    ids.insert(Arc::clone(&id));
+    id
+}
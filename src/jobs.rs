@@ -0,0 +1,569 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::Mutex;
+
+/// Jobs kept to be queried, the oldest ones are forgotten first.
+const MAX_JOBS: usize = 10_000;
+
+/// Error of the updates that followed an update of a job that was forgotten before it was
+/// settled.
+const SUPERSEDED: &str = "superseded";
+
+/// Where a single staking pool refresh requested by a webhook stands.
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum UpdateStatus {
+    /// The receipt is being looked up to find the staking pool and the block, or the
+    /// account to find whether it is a staking pool.
+    Resolving,
+    /// The receipt or its block could not be looked up.
+    Unresolved,
+    /// Waiting for the worker to refresh the staking pool.
+    Queued,
+    Succeeded,
+    Failed,
+    /// The receipt was already received in the same block and its staking pool refreshed, so
+    /// nothing was done.
+    Duplicate,
+    /// The receiver is not a known staking pool, so it was not refreshed.
+    Rejected,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct JobUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt_id: Option<String>,
+    /// Staking pool the update touched, known once the receipt is resolved.
+    pub receiver_id: Option<String>,
+    pub block_height: Option<u64>,
+    pub status: UpdateStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Job handling the same receipt delivered earlier, which this update follows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+/// A webhook call and the staking pool refreshes it requested.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Job {
+    pub id: String,
+    pub created_at: i64,
+    pub status: JobStatus,
+    pub updates: Vec<JobUpdate>,
+}
+
+impl Job {
+    fn refresh_status(&mut self) {
+        self.status = if self.updates.iter().any(|update| {
+            matches!(
+                update.status,
+                UpdateStatus::Resolving | UpdateStatus::Queued
+            )
+        }) {
+            JobStatus::Pending
+        } else if self.updates.iter().all(|update| {
+            matches!(
+                update.status,
+                UpdateStatus::Succeeded | UpdateStatus::Duplicate
+            )
+        }) {
+            JobStatus::Succeeded
+        } else {
+            JobStatus::Failed
+        };
+    }
+}
+
+#[derive(Default)]
+struct JobsInner {
+    jobs: HashMap<String, Job>,
+    /// Job ids from the oldest to the newest.
+    order: VecDeque<String>,
+    /// Queued updates by staking pool, as `(job id, update index)`.
+    queued: HashMap<String, Vec<(String, usize)>>,
+    /// Updates following an unsettled one, by the `(job id, update index)` they follow.
+    linked: HashMap<(String, usize), Vec<(String, usize)>>,
+}
+
+impl JobsInner {
+    fn mark_queued(&mut self, job_id: &str, index: usize) {
+        let Some(update) = self
+            .jobs
+            .get_mut(job_id)
+            .and_then(|job| job.updates.get_mut(index))
+        else {
+            return;
+        };
+        let Some(receiver_id) = update.receiver_id.clone() else {
+            return;
+        };
+
+        update.status = UpdateStatus::Queued;
+        self.queued
+            .entry(receiver_id)
+            .or_default()
+            .push((job_id.to_string(), index));
+        self.propagate(job_id, index);
+    }
+
+    /// Copies where an update stands to the updates following it, which stop following it
+    /// once it is settled.
+    fn propagate(&mut self, job_id: &str, index: usize) {
+        let key = (job_id.to_string(), index);
+        let Some(followers) = self.linked.get(&key).cloned() else {
+            return;
+        };
+        let Some(original) = self
+            .jobs
+            .get(job_id)
+            .and_then(|job| job.updates.get(index))
+            .cloned()
+        else {
+            return;
+        };
+
+        for (follower_id, follower_index) in followers {
+            let Some(job) = self.jobs.get_mut(&follower_id) else {
+                continue;
+            };
+            if let Some(update) = job.updates.get_mut(follower_index) {
+                update.receiver_id.clone_from(&original.receiver_id);
+                update.block_height = original.block_height;
+                update.status = original.status;
+                update.error.clone_from(&original.error);
+            }
+            job.refresh_status();
+        }
+
+        if !matches!(
+            original.status,
+            UpdateStatus::Resolving | UpdateStatus::Queued
+        ) {
+            self.linked.remove(&key);
+        }
+    }
+
+    /// Marks an update that follows an update no longer known as failed, since it would never
+    /// be settled otherwise.
+    fn supersede(&mut self, job_id: &str, index: usize) {
+        let Some(job) = self.jobs.get_mut(job_id) else {
+            return;
+        };
+        if let Some(update) = job.updates.get_mut(index) {
+            update.status = UpdateStatus::Failed;
+            update.error = Some(SUPERSEDED.to_string());
+        }
+        job.refresh_status();
+    }
+
+    /// Forgets a job, failing the updates of other jobs that still followed one of its own.
+    fn forget(&mut self, job_id: &str) {
+        self.jobs.remove(job_id);
+
+        let orphaned: Vec<_> = self
+            .linked
+            .keys()
+            .filter(|(original_id, _)| original_id == job_id)
+            .cloned()
+            .collect();
+        for key in orphaned {
+            for (follower_id, follower_index) in self.linked.remove(&key).unwrap_or_default() {
+                self.supersede(&follower_id, follower_index);
+            }
+        }
+    }
+}
+
+/// Webhook calls handled in the background, so that their status can be queried later.
+#[derive(Default)]
+pub struct Jobs {
+    inner: Mutex<JobsInner>,
+}
+
+impl Jobs {
+    /// Records a new job. Updates that already know their staking pool and block are marked
+    /// as queued, so they have to be queued for refresh only after this returns.
+    pub async fn create(&self, updates: Vec<JobUpdate>) -> Job {
+        let mut inner = self.inner.lock().await;
+
+        let id = loop {
+            let id = format!("{:016x}", rand::random::<u64>());
+            if !inner.jobs.contains_key(&id) {
+                break id;
+            }
+        };
+
+        let queued: Vec<_> = updates
+            .iter()
+            .enumerate()
+            .filter(|(_, update)| update.status == UpdateStatus::Queued)
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut job = Job {
+            id: id.clone(),
+            created_at: chrono::Utc::now().timestamp(),
+            status: JobStatus::Pending,
+            updates,
+        };
+        job.refresh_status();
+        inner.jobs.insert(id.clone(), job);
+        inner.order.push_back(id.clone());
+        for index in queued {
+            inner.mark_queued(&id, index);
+        }
+
+        while inner.order.len() > MAX_JOBS {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.forget(&oldest);
+            }
+        }
+
+        inner.jobs[&id].clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        self.inner.lock().await.jobs.get(id).cloned()
+    }
+
+    /// Makes an update of the job follow the update of another job that handles the same
+    /// receipt, until that one is settled. It fails right away if that job was forgotten.
+    pub async fn link(&self, job_id: &str, index: usize, original: (String, usize)) {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(update) = inner
+            .jobs
+            .get_mut(job_id)
+            .and_then(|job| job.updates.get_mut(index))
+        {
+            update.duplicate_of = Some(original.0.clone());
+        }
+        if !inner.jobs.contains_key(&original.0) {
+            inner.supersede(job_id, index);
+            return;
+        }
+        let (original_id, original_index) = original.clone();
+        inner
+            .linked
+            .entry(original)
+            .or_default()
+            .push((job_id.to_string(), index));
+        inner.propagate(&original_id, original_index);
+    }
+
+    /// Records that a receipt of the job was already refreshed at the same block.
+    pub async fn duplicate(
+        &self,
+        job_id: &str,
+        index: usize,
+        receiver_id: String,
+        block_height: u64,
+    ) {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(job) = inner.jobs.get_mut(job_id) {
+            if let Some(update) = job.updates.get_mut(index) {
+                update.receiver_id = Some(receiver_id);
+                update.block_height = Some(block_height);
+                update.status = UpdateStatus::Duplicate;
+            }
+            job.refresh_status();
+        }
+    }
+
+    /// Records the staking pool and block an update of the job was checked or resolved to. The
+    /// update has to be queued for refresh only after this returns.
+    pub async fn queued(&self, job_id: &str, index: usize, receiver_id: String, block_height: u64) {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(update) = inner
+            .jobs
+            .get_mut(job_id)
+            .and_then(|job| job.updates.get_mut(index))
+        {
+            update.receiver_id = Some(receiver_id);
+            update.block_height = Some(block_height);
+        }
+        inner.mark_queued(job_id, index);
+    }
+
+    /// Records that a receipt of the job could not be looked up.
+    pub async fn unresolved(&self, job_id: &str, index: usize, error: String) {
+        self.settle(job_id, index, UpdateStatus::Unresolved, None, error)
+            .await;
+    }
+
+    /// Records that an update of the job is not for a staking pool.
+    pub async fn rejected(
+        &self,
+        job_id: &str,
+        index: usize,
+        receiver_id: String,
+        block_height: u64,
+        error: String,
+    ) {
+        self.settle(
+            job_id,
+            index,
+            UpdateStatus::Rejected,
+            Some((receiver_id, block_height)),
+            error,
+        )
+        .await;
+    }
+
+    async fn settle(
+        &self,
+        job_id: &str,
+        index: usize,
+        status: UpdateStatus,
+        target: Option<(String, u64)>,
+        error: String,
+    ) {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(job) = inner.jobs.get_mut(job_id) {
+            if let Some(update) = job.updates.get_mut(index) {
+                update.status = status;
+                update.error = Some(error);
+                if let Some((receiver_id, block_height)) = target {
+                    update.receiver_id = Some(receiver_id);
+                    update.block_height = Some(block_height);
+                }
+            }
+            job.refresh_status();
+        }
+        inner.propagate(job_id, index);
+    }
+
+    /// Records the outcome of refreshing `receiver_id` at `block_height`, which settles every
+    /// update queued for that staking pool at that block or an older one.
+    pub async fn refreshed(&self, receiver_id: &str, block_height: u64, error: Option<String>) {
+        let mut inner = self.inner.lock().await;
+        let JobsInner { jobs, queued, .. } = &mut *inner;
+
+        let Some(waiting) = queued.get_mut(receiver_id) else {
+            return;
+        };
+
+        let mut settled = Vec::new();
+        waiting.retain(|(job_id, index)| {
+            // Jobs that were forgotten in the meantime are dropped from the index as well.
+            let Some(job) = jobs.get_mut(job_id) else {
+                return false;
+            };
+            let Some(update) = job.updates.get_mut(*index) else {
+                return false;
+            };
+            if update
+                .block_height
+                .is_some_and(|height| height > block_height)
+            {
+                return true;
+            }
+
+            update.status = if error.is_some() {
+                UpdateStatus::Failed
+            } else {
+                UpdateStatus::Succeeded
+            };
+            update.error.clone_from(&error);
+            job.refresh_status();
+            settled.push((job_id.clone(), *index));
+            false
+        });
+
+        if waiting.is_empty() {
+            queued.remove(receiver_id);
+        }
+        for (job_id, index) in settled {
+            inner.propagate(&job_id, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(
+        receiver_id: Option<&str>,
+        block_height: Option<u64>,
+        status: UpdateStatus,
+    ) -> JobUpdate {
+        JobUpdate {
+            receipt_id: None,
+            receiver_id: receiver_id.map(ToString::to_string),
+            block_height,
+            status,
+            error: None,
+            duplicate_of: None,
+        }
+    }
+
+    fn statuses(job: &Job) -> Vec<UpdateStatus> {
+        job.updates.iter().map(|update| update.status).collect()
+    }
+
+    #[tokio::test]
+    async fn jobs_succeed_once_every_update_is_refreshed() {
+        let jobs = Jobs::default();
+        let job = jobs
+            .create(vec![
+                update(Some("a.poolv1.near"), Some(100), UpdateStatus::Queued),
+                update(None, None, UpdateStatus::Resolving),
+            ])
+            .await;
+        assert_eq!(job.status, JobStatus::Pending);
+
+        jobs.queued(&job.id, 1, "b.poolv1.near".to_string(), 102)
+            .await;
+        jobs.refreshed("a.poolv1.near", 101, None).await;
+        let job = jobs.get(&job.id).await.unwrap();
+        assert_eq!(
+            statuses(&job),
+            [UpdateStatus::Succeeded, UpdateStatus::Queued]
+        );
+        assert_eq!(job.status, JobStatus::Pending);
+
+        // A refresh at an older block than the one requested settles nothing.
+        jobs.refreshed("b.poolv1.near", 101, None).await;
+        assert_eq!(jobs.get(&job.id).await.unwrap().status, JobStatus::Pending);
+
+        jobs.refreshed("b.poolv1.near", 102, None).await;
+        let job = jobs.get(&job.id).await.unwrap();
+        assert_eq!(
+            statuses(&job),
+            [UpdateStatus::Succeeded, UpdateStatus::Succeeded]
+        );
+        assert_eq!(job.status, JobStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn jobs_fail_once_settled_with_any_failed_update() {
+        let jobs = Jobs::default();
+        let job = jobs
+            .create(vec![
+                update(Some("a.poolv1.near"), Some(100), UpdateStatus::Queued),
+                update(None, None, UpdateStatus::Resolving),
+                update(None, None, UpdateStatus::Resolving),
+                update(Some("a.poolv1.near"), Some(100), UpdateStatus::Duplicate),
+            ])
+            .await;
+
+        jobs.unresolved(&job.id, 1, "Unknown receipt".to_string())
+            .await;
+        jobs.rejected(
+            &job.id,
+            2,
+            "token.near".to_string(),
+            100,
+            "Not a staking pool".to_string(),
+        )
+        .await;
+        assert_eq!(jobs.get(&job.id).await.unwrap().status, JobStatus::Pending);
+
+        jobs.refreshed("a.poolv1.near", 100, Some("Timeout".to_string()))
+            .await;
+        let job = jobs.get(&job.id).await.unwrap();
+        assert_eq!(
+            statuses(&job),
+            [
+                UpdateStatus::Failed,
+                UpdateStatus::Unresolved,
+                UpdateStatus::Rejected,
+                UpdateStatus::Duplicate,
+            ]
+        );
+        assert_eq!(job.updates[0].error.as_deref(), Some("Timeout"));
+        assert_eq!(job.updates[2].receiver_id.as_deref(), Some("token.near"));
+        assert_eq!(job.status, JobStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn jobs_without_pending_updates_are_settled_when_created() {
+        let jobs = Jobs::default();
+        let job = jobs
+            .create(vec![update(
+                Some("a.poolv1.near"),
+                Some(100),
+                UpdateStatus::Duplicate,
+            )])
+            .await;
+
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert!(jobs.get("unknown").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn linked_updates_follow_the_original_until_it_is_settled() {
+        let jobs = Jobs::default();
+        let original = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+        let duplicate = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+
+        jobs.link(&duplicate.id, 0, (original.id.clone(), 0)).await;
+        jobs.queued(&original.id, 0, "a.poolv1.near".to_string(), 100)
+            .await;
+        let job = jobs.get(&duplicate.id).await.unwrap();
+        assert_eq!(statuses(&job), [UpdateStatus::Queued]);
+        assert_eq!(job.updates[0].receiver_id.as_deref(), Some("a.poolv1.near"));
+        assert_eq!(job.updates[0].duplicate_of.as_deref(), Some(&*original.id));
+
+        jobs.refreshed("a.poolv1.near", 100, Some("Timeout".to_string()))
+            .await;
+        let job = jobs.get(&duplicate.id).await.unwrap();
+        assert_eq!(statuses(&job), [UpdateStatus::Failed]);
+        assert_eq!(job.status, JobStatus::Failed);
+
+        // An update linked to a settled one takes its outcome right away.
+        let late = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+        jobs.link(&late.id, 0, (original.id.clone(), 0)).await;
+        assert_eq!(jobs.get(&late.id).await.unwrap().status, JobStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn followers_of_a_forgotten_job_are_failed_as_superseded() {
+        let jobs = Jobs::default();
+        let original = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+        let duplicate = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+        jobs.link(&duplicate.id, 0, (original.id.clone(), 0)).await;
+
+        for _ in 1..MAX_JOBS {
+            jobs.create(Vec::new()).await;
+        }
+        assert!(jobs.get(&original.id).await.is_none());
+
+        let job = jobs.get(&duplicate.id).await.unwrap();
+        assert_eq!(statuses(&job), [UpdateStatus::Failed]);
+        assert_eq!(job.updates[0].error.as_deref(), Some(SUPERSEDED));
+        assert_eq!(job.status, JobStatus::Failed);
+        assert!(jobs.inner.lock().await.linked.is_empty());
+
+        // The same goes for an update linked to a job that is already forgotten.
+        let late = jobs
+            .create(vec![update(None, None, UpdateStatus::Resolving)])
+            .await;
+        jobs.link(&late.id, 0, (original.id.clone(), 0)).await;
+        assert_eq!(jobs.get(&late.id).await.unwrap().status, JobStatus::Failed);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::config::LakeConfig;
+use crate::follower::{BlockSource, FunctionCall, STAKING_METHODS};
+
+use color_eyre::{eyre::Context, Result};
+use futures::StreamExt;
+use object_store::{path::Path, ObjectStore};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Objects listed at most to find the latest block, so that a bucket with the whole history
+/// is never listed to the end.
+const MAX_LISTED_OBJECTS: usize = 1000;
+
+#[derive(Deserialize)]
+struct LakeChunkHeader {
+    shard_id: u64,
+}
+
+/// The part of `block.json` the follower needs.
+#[derive(Deserialize)]
+struct LakeBlock {
+    chunks: Vec<LakeChunkHeader>,
+}
+
+#[derive(Deserialize)]
+struct LakeOutcome {
+    status: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct LakeExecutionOutcome {
+    outcome: LakeOutcome,
+}
+
+#[derive(Deserialize)]
+struct LakeReceipt {
+    receiver_id: String,
+    receipt: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct LakeReceiptExecution {
+    execution_outcome: LakeExecutionOutcome,
+    receipt: LakeReceipt,
+}
+
+/// The part of `shard_N.json` the follower needs. Actions and statuses are kept as raw JSON,
+/// so that kinds added by newer protocol versions do not break parsing.
+#[derive(Deserialize)]
+struct LakeShard {
+    #[serde(default)]
+    receipt_execution_outcomes: Vec<LakeReceiptExecution>,
+}
+
+impl LakeReceiptExecution {
+    fn succeeded(&self) -> bool {
+        self.execution_outcome
+            .outcome
+            .status
+            .as_object()
+            .is_some_and(|status| status.keys().any(|kind| kind.starts_with("Success")))
+    }
+
+    fn method_names(&self) -> impl Iterator<Item = &str> {
+        self.receipt
+            .receipt
+            .pointer("/Action/actions")
+            .and_then(serde_json::Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|action| action.pointer("/FunctionCall/method_name")?.as_str())
+    }
+}
+
+/// Reads blocks laid out like NEAR Lake: a `<block height padded to 12 digits>/` prefix per
+/// block holding `block.json` and one `shard_<id>.json` per shard.
+///
+/// Receipts come with their execution outcomes, so only calls that succeeded are reported.
+pub struct LakeBlockSource {
+    store: Arc<dyn ObjectStore>,
+    /// Calls of the block read last, which the follower asks about once per staking pool.
+    last_block: Mutex<Option<(u64, Option<Vec<FunctionCall>>)>>,
+}
+
+impl LakeBlockSource {
+    pub fn open(config: &LakeConfig) -> Result<Self> {
+        let store: Arc<dyn ObjectStore> = match config {
+            LakeConfig::Directory { path } => Arc::new(
+                object_store::local::LocalFileSystem::new_with_prefix(path).with_context(|| {
+                    format!("Failed to open lake directory <{}>", path.display())
+                })?,
+            ),
+            LakeConfig::Bucket {
+                bucket,
+                region,
+                endpoint,
+                requester_pays,
+            } => {
+                // Credentials are taken from the usual `AWS_*` environment variables.
+                let mut builder = object_store::aws::AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .with_region(region);
+                if let Some(endpoint) = endpoint {
+                    builder = builder.with_endpoint(endpoint).with_allow_http(true);
+                }
+                if *requester_pays {
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    headers.insert(
+                        "x-amz-request-payer",
+                        reqwest::header::HeaderValue::from_static("requester"),
+                    );
+                    builder = builder.with_client_options(
+                        object_store::ClientOptions::new().with_default_headers(headers),
+                    );
+                }
+
+                Arc::new(
+                    builder
+                        .build()
+                        .with_context(|| format!("Failed to open lake bucket <{bucket}>"))?,
+                )
+            }
+        };
+
+        Ok(Self {
+            store,
+            last_block: Mutex::new(None),
+        })
+    }
+
+    /// Content of `location`, or `None` if there is no such object.
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, location: &Path) -> Result<Option<T>> {
+        let bytes = match self.store.get(location).await {
+            Ok(result) => result.bytes().await?,
+            Err(object_store::Error::NotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read <{location}>")),
+        };
+
+        serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("Failed to parse <{location}>"))
+    }
+
+    /// Function calls of the receipts that succeeded in the block at `height`, or `None` if
+    /// no block was produced at that height.
+    async fn function_calls(&self, height: u64) -> Result<Option<Vec<FunctionCall>>> {
+        let mut last_block = self.last_block.lock().await;
+        if let Some((last_height, calls)) = &*last_block {
+            if *last_height == height {
+                return Ok(calls.clone());
+            }
+        }
+
+        let calls = self.read_function_calls(height).await?;
+        *last_block = Some((height, calls.clone()));
+        Ok(calls)
+    }
+
+    async fn read_function_calls(&self, height: u64) -> Result<Option<Vec<FunctionCall>>> {
+        let prefix = block_prefix(height);
+
+        let Some(block) = self
+            .get_json::<LakeBlock>(&Path::from(format!("{prefix}/block.json")))
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let mut calls = Vec::new();
+        for chunk in block.chunks {
+            let location = Path::from(format!("{prefix}/shard_{}.json", chunk.shard_id));
+            let Some(shard) = self.get_json::<LakeShard>(&location).await? else {
+                color_eyre::eyre::bail!("Missing <{location}>");
+            };
+
+            for execution in shard
+                .receipt_execution_outcomes
+                .iter()
+                .filter(|execution| execution.succeeded())
+            {
+                calls.extend(execution.method_names().map(|method_name| FunctionCall {
+                    receiver_id: execution.receipt.receiver_id.clone(),
+                    method_name: method_name.to_string(),
+                    block_height: height,
+                }));
+            }
+        }
+
+        Ok(Some(calls))
+    }
+}
+
+fn block_prefix(height: u64) -> String {
+    format!("{height:012}")
+}
+
+#[rocket::async_trait]
+impl BlockSource for LakeBlockSource {
+    async fn final_height(&self, after: Option<u64>) -> Result<u64> {
+        // Listing a bucket with the whole history from the start would only find blocks near
+        // genesis, hence `follower_start_height` being required.
+        let Some(after) = after else {
+            color_eyre::eyre::bail!(
+                "The latest block of a lake is only looked up after a given one"
+            );
+        };
+
+        // `~` sorts after every file name of a block.
+        let mut objects = self
+            .store
+            .list_with_offset(None, &Path::from(format!("{}/~", block_prefix(after))))
+            .take(MAX_LISTED_OBJECTS);
+
+        let mut final_height = after;
+        while let Some(object) = objects.next().await {
+            let object = object.context("Failed to list lake blocks")?;
+            let height = object
+                .location
+                .parts()
+                .next()
+                .and_then(|prefix| prefix.as_ref().parse::<u64>().ok());
+            final_height = final_height.max(height.unwrap_or_default());
+        }
+
+        Ok(final_height)
+    }
+
+    async fn touched_accounts(&self, height: u64) -> Result<Option<BTreeSet<String>>> {
+        Ok(self
+            .function_calls(height)
+            .await?
+            .map(|calls| calls.into_iter().map(|call| call.receiver_id).collect()))
+    }
+
+    async fn staking_calls(&self, height: u64, account_id: &str) -> Result<Vec<FunctionCall>> {
+        Ok(self
+            .function_calls(height)
+            .await?
+            .into_iter()
+            .flatten()
+            .filter(|call| {
+                call.receiver_id == account_id
+                    && STAKING_METHODS.contains(&call.method_name.as_str())
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> LakeBlockSource {
+        LakeBlockSource::open(&LakeConfig::Directory {
+            path: std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lake"),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn final_height_lists_blocks_after_the_given_one() {
+        let lake = fixture();
+
+        assert_eq!(lake.final_height(Some(100)).await.unwrap(), 103);
+        assert_eq!(lake.final_height(Some(103)).await.unwrap(), 103);
+        assert_eq!(lake.final_height(Some(200)).await.unwrap(), 200);
+        assert!(lake.final_height(None).await.is_err());
+    }
+
+    async fn staking_methods(lake: &LakeBlockSource, height: u64, account_id: &str) -> Vec<String> {
+        lake.staking_calls(height, account_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|call| {
+                assert_eq!(call.block_height, height);
+                call.method_name
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_successful_receipts_are_reported() {
+        let lake = fixture();
+
+        assert_eq!(
+            lake.touched_accounts(101).await.unwrap(),
+            Some(BTreeSet::from([
+                "a.poolv1.near".to_string(),
+                "c.poolv1.near".to_string(),
+                "token.near".to_string(),
+            ]))
+        );
+        assert_eq!(
+            staking_methods(&lake, 101, "a.poolv1.near").await,
+            ["deposit_and_stake"]
+        );
+        assert_eq!(
+            staking_methods(&lake, 101, "c.poolv1.near").await,
+            ["withdraw"]
+        );
+        // The `stake` call failed.
+        assert!(staking_methods(&lake, 101, "b.poolv1.near")
+            .await
+            .is_empty());
+
+        assert_eq!(lake.touched_accounts(102).await.unwrap(), None);
+        assert_eq!(
+            lake.touched_accounts(103).await.unwrap(),
+            Some(BTreeSet::new())
+        );
+    }
+}
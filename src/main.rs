@@ -1,126 +1,847 @@
+mod auth;
+mod changelog;
+mod config;
 mod delegators;
+mod evictions;
 mod extensions;
+mod follower;
+mod history;
+mod index;
+mod interner;
+mod jobs;
+mod lake;
 mod methods;
+mod network;
+mod receipts;
+mod resync;
+mod rpc;
+mod snapshot;
+mod store;
+mod webhook;
 
 #[macro_use]
 extern crate rocket;
 
 use std::io::Write;
 
-use near_jsonrpc_client::JsonRpcClient;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
+use rocket::response::content::RawJson;
+use rocket::response::stream::ByteStream;
 use rocket::serde::json::Json;
-use rocket::State;
 
 use color_eyre::Result;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::collections::BTreeMap;
-
 use std::sync::Arc;
-use tokio::sync::{mpsc::Sender, RwLock};
 
-#[derive(Debug, Deserialize, Serialize)]
-struct WebhookData {
-    payload: Payload,
+use network::{AppState, NetworkState};
+
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const MAX_PAGE_LIMIT: usize = 1000;
+const STREAM_CHUNK_SIZE: usize = 1000;
+
+#[derive(Debug, FromForm)]
+struct DelegatorsQuery<'r> {
+    after: Option<&'r str>,
+    limit: Option<usize>,
+    prefix: Option<&'r str>,
+    suffix: Option<&'r str>,
+    include_dust: Option<bool>,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Payload {
-    #[serde(rename = "Actions")]
-    actions: Actions,
-}
+impl<'r> DelegatorsQuery<'r> {
+    const fn filter(&self) -> delegators::DelegatorsFilter<'r> {
+        delegators::DelegatorsFilter {
+            after: self.after,
+            prefix: self.prefix,
+            suffix: self.suffix,
+        }
+    }
+
+    const fn is_paginated(&self) -> bool {
+        self.after.is_some()
+            || self.limit.is_some()
+            || self.prefix.is_some()
+            || self.suffix.is_some()
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Actions {
-    receipt_id: Option<String>,
-    block_hash: Option<String>,
+    fn page_limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    /// The whole dump, or a single page of it when any of the pagination or filtering
+    /// parameters is given.
+    fn to_json(
+        &self,
+        delegators: &delegators::DelegatorsWithTimestamp,
+    ) -> serde_json::Result<String> {
+        if self.is_paginated() {
+            serde_json::to_string(&delegators.page(self.filter(), self.page_limit()))
+        } else {
+            serde_json::to_string(delegators)
+        }
+    }
 }
 
-#[derive(Clone)]
-struct AppState {
-    validators_to_process: Arc<RwLock<BTreeMap<String, u64>>>,
-    validators_state: Arc<RwLock<delegators::ValidatorsWithTimestamp>>,
-    delegators_state: Arc<RwLock<delegators::DelegatorsWithTimestamp>>,
-    tx: Sender<()>,
+/// The latest historical snapshot at or before `at_block` (a block height) or `at_time`
+/// (a Unix timestamp in seconds), or `None` for the live index when neither is given.
+async fn historical_validators(
+    state: &NetworkState,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
+) -> Result<Option<Arc<delegators::ValidatorsWithTimestamp>>, Status> {
+    let point = match (at_block, at_time) {
+        (None, None) => return Ok(None),
+        (Some(block_height), None) => history::PointInTime::BlockHeight(block_height),
+        (None, Some(timestamp)) => history::PointInTime::Timestamp(timestamp),
+        (Some(_), Some(_)) => return Err(Status::BadRequest),
+    };
+    let Some(history) = &state.history else {
+        return Err(Status::NotFound);
+    };
+
+    match history.load(point).await {
+        Ok(Some(validators)) => Ok(Some(validators)),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            error!("Failed to load historical snapshot: {:#}", e);
+            Err(Status::InternalServerError)
+        }
+    }
 }
 
-#[get("/get-staking-pools")]
-async fn get_all(state: &State<AppState>) -> Json<delegators::DelegatorsWithTimestamp> {
+/// Dust positions (see `min_delegation` in the configuration) are left out unless
+/// `include_dust=true` is passed.
+#[get("/get-staking-pools?<query..>")]
+async fn get_all(
+    query: DelegatorsQuery<'_>,
+    state: &NetworkState,
+) -> Result<RawJson<Arc<str>>, Status> {
     info!("GET request received");
 
-    Json(state.delegators_state.read().await.clone())
+    let snapshot = historical_validators(state, query.at_block, query.at_time).await?;
+
+    let json = if let Some(snapshot) = snapshot {
+        query
+            .to_json(&delegators::DelegatorsWithTimestamp::from_validators(
+                &snapshot,
+                state.min_delegation(query.include_dust),
+            ))
+            .map(Arc::from)
+    } else {
+        let current = state.snapshot();
+        if query.include_dust.unwrap_or(false) || state.config.lookups_from_store {
+            query
+                .to_json(&delegators::DelegatorsWithTimestamp {
+                    version: Some(current.version),
+                    ..delegators::DelegatorsWithTimestamp::from_validators(
+                        &current.validators,
+                        state.min_delegation(query.include_dust),
+                    )
+                })
+                .map(Arc::from)
+        } else if query.is_paginated() {
+            query.to_json(&current.delegators).map(Arc::from)
+        } else {
+            current.full_dump()
+        }
+    };
+
+    json.map(RawJson).map_err(|e| {
+        error!("Failed to serialize delegators: {}", e);
+        Status::InternalServerError
+    })
+}
+
+/// Response with the version of the snapshot it was read from in the `X-Index-Version`
+/// header, left out for historical snapshots.
+struct Versioned<R> {
+    inner: R,
+    version: Option<u64>,
 }
 
-#[get("/get-staking-pools/<account_id>")]
+impl<R> Versioned<R> {
+    const fn new(inner: R, version: Option<u64>) -> Self {
+        Self { inner, version }
+    }
+}
+
+impl<'r, 'o: 'r, R: rocket::response::Responder<'r, 'o>> rocket::response::Responder<'r, 'o>
+    for Versioned<R>
+{
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+        let mut response = self.inner.respond_to(request)?;
+        if let Some(version) = self.version {
+            response.set_raw_header("X-Index-Version", version.to_string());
+        }
+        Ok(response)
+    }
+}
+
+/// Writes up to `limit` delegators matching `filter` as NDJSON lines and returns the last
+/// written account id, if any.
+fn write_ndjson_chunk(
+    delegators: &delegators::DelegatorsWithTimestamp,
+    filter: delegators::DelegatorsFilter,
+    limit: usize,
+    buffer: &mut Vec<u8>,
+) -> Option<String> {
+    let mut last_account_id = None;
+
+    for (account_id, staking_pools) in delegators.iter_filtered(filter).take(limit) {
+        if serde_json::to_writer(
+            &mut *buffer,
+            &delegators::DelegatorStakingPools {
+                account_id,
+                staking_pools,
+            },
+        )
+        .is_err()
+        {
+            break;
+        }
+        buffer.push(b'\n');
+        last_account_id = Some(account_id.to_string());
+    }
+
+    last_account_id
+}
+
+/// Same as `/get-staking-pools`, but written as one `{"account_id", "staking_pools"}` line per
+/// delegator. Entries are read in chunks from a single snapshot, whose version is given in the
+/// `X-Index-Version` header, so the index is never cloned as a whole (unless
+/// `include_dust=true` or a point in time is given, or lookups are answered from the store)
+/// and `limit` is not capped.
+#[get("/stream-staking-pools?<query..>")]
+async fn stream_all<'r>(
+    query: DelegatorsQuery<'r>,
+    state: &'r NetworkState,
+) -> Result<Versioned<(ContentType, ByteStream![Vec<u8> + 'r])>, Status> {
+    info!("GET stream request received");
+
+    let snapshot = historical_validators(state, query.at_block, query.at_time).await?;
+    let current = state.snapshot();
+
+    let materialized_delegators = if let Some(snapshot) = &snapshot {
+        Some(delegators::DelegatorsWithTimestamp::from_validators(
+            snapshot,
+            state.min_delegation(query.include_dust),
+        ))
+    } else if query.include_dust.unwrap_or(false) || state.config.lookups_from_store {
+        Some(delegators::DelegatorsWithTimestamp::from_validators(
+            &current.validators,
+            state.min_delegation(query.include_dust),
+        ))
+    } else {
+        None
+    };
+    let version = snapshot.is_none().then_some(current.version);
+
+    let stream = ByteStream! {
+        let mut after = query.after.map(ToString::to_string);
+        let mut remaining = query.limit.unwrap_or(usize::MAX);
+
+        while remaining > 0 {
+            let chunk_size = remaining.min(STREAM_CHUNK_SIZE);
+            let filter = delegators::DelegatorsFilter {
+                after: after.as_deref(),
+                ..query.filter()
+            };
+
+            let mut buffer = Vec::new();
+            let last_account_id = match &materialized_delegators {
+                Some(delegators) => write_ndjson_chunk(delegators, filter, chunk_size, &mut buffer),
+                None => write_ndjson_chunk(&current.delegators, filter, chunk_size, &mut buffer),
+            };
+
+            let Some(last_account_id) = last_account_id else {
+                break;
+            };
+
+            remaining -= chunk_size;
+            after = Some(last_account_id);
+            yield buffer;
+        }
+    };
+
+    Ok(Versioned::new(
+        (ContentType::new("application", "x-ndjson"), stream),
+        version,
+    ))
+}
+
+/// Without a point in time, an account that is not delegating at all is answered with a 503,
+/// as the index may not be loaded yet.
+#[get("/get-staking-pools/<account_id>?<include_dust>&<at_block>&<at_time>")]
 async fn get_by_account_id(
     account_id: &str,
-    state: &State<AppState>,
+    include_dust: Option<bool>,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
+    state: &NetworkState,
 ) -> Result<(Status, Json<delegators::DelegatorWithTimestamp>), Status> {
     info!("GET by account id request received");
 
-    let locked_delegators_state = state.delegators_state.read().await;
-
-    locked_delegators_state
-        .delegator_staking_pools
-        .get(account_id)
-        .map_or_else(
-            || Err(Status::new(503)),
-            |delegators| {
-                Ok((
-                    Status::Ok,
-                    Json(delegators::DelegatorWithTimestamp {
-                        timestamp: locked_delegators_state.timestamp,
-                        delegator_staking_pools: delegators.clone(),
-                    }),
-                ))
-            },
-        )
-}
+    let min_delegation = state.min_delegation(include_dust);
+    let snapshot = historical_validators(state, at_block, at_time).await?;
 
-#[post("/update-staking-pools", data = "<data>")]
-async fn update(data: Json<WebhookData>, state: &State<AppState>) -> Status {
-    info!("POST request received");
+    let stored_balances = if snapshot.is_none() && state.config.lookups_from_store {
+        state.store.lookup(account_id).await.map_err(|e| {
+            error!("Failed to read delegator from the store: {:#}", e);
+            Status::InternalServerError
+        })?
+    } else {
+        None
+    };
 
-    let Some(receipt_id) = data.payload.actions.receipt_id.clone() else {
-        return Status::InternalServerError;
+    let current = state.snapshot();
+    let validators_state = match &snapshot {
+        Some(snapshot) => snapshot.as_ref(),
+        None => &current.validators,
     };
-    let Some(block_hash) = data.payload.actions.block_hash.clone() else {
-        return Status::InternalServerError;
+    // Rows read from the store are as of its last flush rather than of a snapshot.
+    let version = (snapshot.is_none() && stored_balances.is_none()).then_some(current.version);
+    let balances = match stored_balances {
+        Some(stored_balances) => stored_balances
+            .into_iter()
+            .filter(|(validator, stake)| {
+                stake.total() >= validators_state.min_delegation_of(validator, min_delegation)
+            })
+            .collect(),
+        None => validators_state.delegator_stakes(account_id, min_delegation),
     };
-    let Ok(block_hash) = block_hash.parse::<near_primitives::hash::CryptoHash>() else {
-        return Status::InternalServerError;
+
+    if balances.is_empty() && snapshot.is_none() {
+        return Err(Status::new(503));
+    }
+
+    Ok((
+        Status::Ok,
+        Json(delegators::DelegatorWithTimestamp {
+            timestamp: validators_state.timestamp,
+            version,
+            delegator_staking_pools: balances.keys().cloned().collect(),
+            staking_pools_metadata: validators_state.metadata_of(balances.keys()),
+            balances,
+        }),
+    ))
+}
+
+#[get("/validators?<include_dust>&<at_block>&<at_time>")]
+async fn get_validators(
+    include_dust: Option<bool>,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
+    state: &NetworkState,
+) -> Result<Json<delegators::ValidatorsSummaryWithTimestamp>, Status> {
+    info!("GET validators request received");
+
+    let min_delegation = state.min_delegation(include_dust);
+
+    Ok(Json(
+        match historical_validators(state, at_block, at_time).await? {
+            Some(snapshot) => snapshot.summary(min_delegation),
+            None => {
+                let current = state.snapshot();
+                delegators::ValidatorsSummaryWithTimestamp {
+                    version: Some(current.version),
+                    ..current.validators.summary(min_delegation)
+                }
+            }
+        },
+    ))
+}
+
+#[get(
+    "/validators/<validator_account_id>/delegators?<after>&<limit>&<include_dust>&<at_block>&<at_time>"
+)]
+async fn get_validator_delegators(
+    validator_account_id: &str,
+    after: Option<&str>,
+    limit: Option<usize>,
+    include_dust: Option<bool>,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
+    state: &NetworkState,
+) -> Result<Json<delegators::ValidatorDelegatorsWithTimestamp>, Status> {
+    info!("GET validator delegators request received");
+
+    let snapshot = historical_validators(state, at_block, at_time).await?;
+    let current = state.snapshot();
+    let (validators_state, version) = match &snapshot {
+        Some(snapshot) => (snapshot.as_ref(), None),
+        None => (&current.validators, Some(current.version)),
     };
 
-    let beta_json_rpc_client = JsonRpcClient::connect("https://beta.rpc.mainnet.near.org");
+    validators_state
+        .validator_delegators(
+            validator_account_id,
+            after,
+            limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+            state.min_delegation(include_dust),
+        )
+        .map(|delegators| {
+            Json(delegators::ValidatorDelegatorsWithTimestamp {
+                version,
+                ..delegators
+            })
+        })
+        .ok_or(Status::NotFound)
+}
 
+#[get("/validators/<validator_account_id>/delegators/count?<include_dust>&<at_block>&<at_time>")]
+async fn get_validator_delegators_count(
+    validator_account_id: &str,
+    include_dust: Option<bool>,
+    at_block: Option<u64>,
+    at_time: Option<i64>,
+    state: &NetworkState,
+) -> Result<Json<delegators::ValidatorDelegatorsCountWithTimestamp>, Status> {
+    info!("GET validator delegators count request received");
+
+    let min_delegation = state.min_delegation(include_dust);
+
+    match historical_validators(state, at_block, at_time).await? {
+        Some(snapshot) => snapshot.validator_delegators_count(validator_account_id, min_delegation),
+        None => {
+            let current = state.snapshot();
+            current
+                .validators
+                .validator_delegators_count(validator_account_id, min_delegation)
+                .map(|count| delegators::ValidatorDelegatorsCountWithTimestamp {
+                    version: Some(current.version),
+                    ..count
+                })
+        }
+    }
+    .map(Json)
+    .ok_or(Status::NotFound)
+}
+
+/// Delegators joining and leaving staking pools, oldest first, after the `since` cursor.
+#[get("/changes?<since>&<limit>")]
+async fn get_changes(
+    since: Option<u64>,
+    limit: Option<usize>,
+    state: &NetworkState,
+) -> Json<changelog::ChangesPage> {
+    info!("GET changes request received");
+
+    Json(
+        state
+            .change_log
+            .since(
+                since.unwrap_or(0),
+                None,
+                limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+            )
+            .await,
+    )
+}
+
+#[get("/validators/<validator_account_id>/changes?<since>&<limit>")]
+async fn get_validator_changes(
+    validator_account_id: &str,
+    since: Option<u64>,
+    limit: Option<usize>,
+    state: &NetworkState,
+) -> Json<changelog::ChangesPage> {
+    info!("GET validator changes request received");
+
+    Json(
+        state
+            .change_log
+            .since(
+                since.unwrap_or(0),
+                Some(validator_account_id),
+                limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+            )
+            .await,
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct FullResyncRequired {
+    full_resync_required: bool,
+    /// Current version of the index.
+    version: u64,
+}
+
+/// Delegator -> staking pool mutations since `since_version`, taken from the `version` of a
+/// `/get-staking-pools` response or of a previous call. Answers 410 when those mutations are
+/// no longer kept.
+#[get("/sync?<since_version>&<limit>")]
+async fn sync(
+    since_version: u64,
+    limit: Option<usize>,
+    state: &NetworkState,
+) -> Result<Json<changelog::SyncPage>, (Status, Json<FullResyncRequired>)> {
+    info!("GET sync request received");
+
+    match state
+        .change_log
+        .sync_since(
+            since_version,
+            limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT),
+        )
+        .await
+    {
+        Some(page) => Ok(Json(page)),
+        None => Err((
+            Status::Gone,
+            Json(FullResyncRequired {
+                full_resync_required: true,
+                version: state.change_log.version().await,
+            }),
+        )),
+    }
+}
+
+/// Looks up the staking pool and the height of the block a receipt was delivered in. The
+/// staking pool of a receipt already received in another block is taken from the cache.
+async fn resolve_receipt(
+    state: &NetworkState,
+    receipt_id: near_primitives::hash::CryptoHash,
+    block_hash: near_primitives::hash::CryptoHash,
+) -> Result<receipts::ResolvedReceipt, String> {
     let block_reference = near_primitives::types::BlockReference::BlockId(
         near_primitives::types::BlockId::Hash(block_hash),
     );
-    let Ok(block_id) = methods::get_block_id(&beta_json_rpc_client, block_reference).await else {
-        return Status::InternalServerError;
-    };
+    let block_height = methods::get_block_id(&state.lookup_rpc_pool, block_reference)
+        .await
+        .map_err(|e| format!("Failed to fetch block {block_hash}: {e}"))?;
 
-    if let Ok(receiver_id) = methods::get_receiver_id(&beta_json_rpc_client, receipt_id).await {
-        state
-            .validators_to_process
-            .write()
+    let receiver_id = match state.receipts.get(&receipt_id).await {
+        Some(cached) => cached.receiver_id,
+        None => methods::get_receiver_id(&state.lookup_rpc_pool, receipt_id.to_string())
             .await
-            .entry(receiver_id)
-            .and_modify(|prev_block_id| {
-                if *prev_block_id < block_id {
-                    *prev_block_id = block_id;
+            .map_err(|e| format!("Failed to fetch receipt {receipt_id}: {e}"))?,
+    };
+
+    Ok(receipts::ResolvedReceipt {
+        receipt_id,
+        block_hash,
+        receiver_id,
+        block_height,
+    })
+}
+
+/// Looks up the receipts of a job and checks the accounts it names, given with the index of
+/// their update, then queues the staking pools among them and wakes up the worker. Receipts
+/// that will not be refreshed are released, so that they are handled again when delivered
+/// again.
+async fn run_update_job(
+    state: NetworkState,
+    job_id: String,
+    requests: Vec<(usize, webhook::UpdateRequest)>,
+) {
+    let network = state.config.network;
+
+    for (index, request) in requests {
+        let (receiver_id, block_height, receipt) = match request {
+            webhook::UpdateRequest::Resolved {
+                receiver_id,
+                block_height,
+            } => (receiver_id, block_height, None),
+            webhook::UpdateRequest::Receipt {
+                receipt_id,
+                block_hash,
+            } => match resolve_receipt(&state, receipt_id, block_hash).await {
+                Ok(resolved) => {
+                    let target = (resolved.receiver_id.clone(), resolved.block_height);
+                    state.receipts.resolved(resolved).await;
+                    (target.0, target.1, Some((receipt_id, block_hash)))
                 }
-            })
-            .or_insert(block_id);
+                Err(error) => {
+                    error!("[{network}] Job {job_id}: {error}");
+                    state.receipts.release(receipt_id, block_hash).await;
+                    state.jobs.unresolved(&job_id, index, error).await;
+                    continue;
+                }
+            },
+        };
+
+        let verified = state.verify_staking_pool(&receiver_id).await;
+        if !matches!(verified, Ok(true)) {
+            if let Some((receipt_id, block_hash)) = receipt {
+                state.receipts.release(receipt_id, block_hash).await;
+            }
+        }
+        match verified {
+            Ok(true) => {
+                state
+                    .jobs
+                    .queued(&job_id, index, receiver_id.clone(), block_height)
+                    .await;
+                state.enqueue(receiver_id, block_height).await;
+            }
+            Ok(false) => {
+                warn!("[{network}] Job {job_id}: ignoring <{receiver_id}>, not a staking pool");
+                state
+                    .jobs
+                    .rejected(
+                        &job_id,
+                        index,
+                        receiver_id,
+                        block_height,
+                        "Not a known staking pool".to_string(),
+                    )
+                    .await;
+            }
+            Err(e) => {
+                let error =
+                    format!("Failed to check whether <{receiver_id}> is a staking pool: {e:#}");
+                error!("[{network}] Job {job_id}: {error}");
+                state
+                    .jobs
+                    .rejected(&job_id, index, receiver_id, block_height, error)
+                    .await;
+            }
+        }
+    }
 
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+    if state.tx.send(()).await.is_err() {
+        error!("[{network}] Failed to send message to the worker");
+    }
+}
+
+/// Queues the staking pools named by a webhook payload to be refreshed and answers right away
+/// with a job to follow at `/jobs/<id>`. See [`webhook::parse`] for the accepted formats; a
+/// payload that does not match any of them is answered with 400 and a JSON error.
+#[post("/update-staking-pools", data = "<data>")]
+async fn update(
+    authorized: auth::Authorized<'_>,
+    data: rocket::Data<'_>,
+    limits: &rocket::data::Limits,
+    state: &NetworkState,
+) -> Result<
+    rocket::response::status::Accepted<Json<jobs::Job>>,
+    (Status, Json<webhook::WebhookError>),
+> {
+    info!("POST request received");
+
+    let reject = |status: Status, error: String| {
+        warn!("Rejected update: {error}");
+        (status, Json(webhook::WebhookError::new(error)))
+    };
+
+    let body = data
+        .open(limits.get("json").unwrap_or(rocket::data::Limits::JSON))
+        .into_bytes()
+        .await
+        .map_err(|e| reject(Status::BadRequest, format!("Failed to read the body: {e}")))?;
+    if !body.is_complete() {
+        return Err(reject(
+            Status::PayloadTooLarge,
+            "Body is larger than the `json` limit".to_string(),
+        ));
+    }
 
-        if state.tx.send(()).await.is_err() {
-            error!("Failed to send message to the worker");
+    authorized
+        .verify_body(&body)
+        .map_err(|error| reject(Status::Unauthorized, error))?;
+
+    let data = serde_json::from_slice::<serde_json::Value>(&body)
+        .map_err(|e| reject(Status::BadRequest, format!("Invalid JSON: {e}")))?;
+    let requests = webhook::parse(&data).map_err(|error| reject(Status::BadRequest, error))?;
+
+    // Known staking pools are queued right away. Receipts are claimed once the job exists:
+    // one already refreshed at the same block is acknowledged without being looked up or
+    // refreshed again, and one being handled by another job follows that job. Everything else
+    // is looked up in the background.
+    let mut updates = Vec::with_capacity(requests.len());
+    let mut queued = Vec::new();
+    let mut pending = Vec::new();
+    let mut receipts = Vec::new();
+    for (index, request) in requests.into_iter().enumerate() {
+        let update = match &request {
+            webhook::UpdateRequest::Resolved {
+                receiver_id,
+                block_height,
+            } => {
+                let (status, error) = if state.is_staking_pool(receiver_id) {
+                    queued.push((receiver_id.clone(), *block_height));
+                    (jobs::UpdateStatus::Queued, None)
+                } else if state.config.staking_pool_code_hashes.is_empty()
+                    && !state.is_factory_account(receiver_id)
+                {
+                    warn!("Ignoring <{receiver_id}>, not a staking pool");
+                    (
+                        jobs::UpdateStatus::Rejected,
+                        Some("Not a known staking pool".to_string()),
+                    )
+                } else {
+                    pending.push((index, request.clone()));
+                    (jobs::UpdateStatus::Resolving, None)
+                };
+
+                jobs::JobUpdate {
+                    receipt_id: None,
+                    receiver_id: Some(receiver_id.clone()),
+                    block_height: Some(*block_height),
+                    status,
+                    error,
+                    duplicate_of: None,
+                }
+            }
+            webhook::UpdateRequest::Receipt {
+                receipt_id,
+                block_hash,
+            } => {
+                receipts.push((index, *receipt_id, *block_hash, request.clone()));
+                jobs::JobUpdate {
+                    receipt_id: Some(receipt_id.to_string()),
+                    receiver_id: None,
+                    block_height: None,
+                    status: jobs::UpdateStatus::Resolving,
+                    error: None,
+                    duplicate_of: None,
+                }
+            }
+        };
+        updates.push(update);
+    }
+    let mut job = state.jobs.create(updates).await;
+
+    for (receiver_id, block_height) in queued {
+        state.enqueue(receiver_id, block_height).await;
+    }
+
+    if !receipts.is_empty() {
+        for (index, receipt_id, block_hash, request) in receipts {
+            match state
+                .receipts
+                .claim(receipt_id, block_hash, &job.id, index)
+                .await
+            {
+                receipts::Claim::Seen(seen) => {
+                    state
+                        .jobs
+                        .duplicate(&job.id, index, seen.receiver_id, seen.block_height)
+                        .await;
+                }
+                receipts::Claim::InFlight(original_id, original_index) => {
+                    state
+                        .jobs
+                        .link(&job.id, index, (original_id, original_index))
+                        .await;
+                }
+                receipts::Claim::New => pending.push((index, request)),
+            }
+        }
+        if let Some(claimed) = state.jobs.get(&job.id).await {
+            job = claimed;
         }
     }
 
-    Status::Ok
+    tokio::spawn(run_update_job(state.clone(), job.id.clone(), pending));
+
+    Ok(rocket::response::status::Accepted(Json(job)))
+}
+
+/// Status of a job created by `/update-staking-pools`. Jobs are kept in memory, so they are
+/// forgotten on restart.
+#[get("/jobs/<id>")]
+async fn get_job(id: &str, state: &NetworkState) -> Option<Json<jobs::Job>> {
+    state.jobs.get(id).await.map(Json)
+}
+
+/// Staking pools missing from the pool factories that are in their grace period, and the
+/// ones that were evicted from the index.
+#[get("/evictions")]
+async fn get_evictions(state: &NetworkState) -> Json<evictions::EvictionsReport> {
+    Json(state.evictions.report().await)
+}
+
+/// Outcome of the latest full resync, 404 until one was started.
+#[get("/resync")]
+async fn get_resync(state: &NetworkState) -> Option<Json<resync::ResyncStatus>> {
+    state.resync.read().await.clone().map(Json)
+}
+
+#[derive(Debug, Serialize)]
+struct RpcHealth {
+    lookup: Vec<rpc::EndpointHealth>,
+    query: Vec<rpc::EndpointHealth>,
+}
+
+#[get("/rpc-health")]
+fn rpc_health(state: &NetworkState) -> Json<RpcHealth> {
+    Json(RpcHealth {
+        lookup: state.lookup_rpc_pool.health(),
+        query: state.query_rpc_pool.health(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct MemoryReport {
+    /// Version of the snapshot the counts are taken from.
+    version: u64,
+    staking_pools: usize,
+    delegators: usize,
+    /// Delegations, counting dust positions too.
+    positions: usize,
+    /// Distinct account ids held by the index.
+    interned_ids: usize,
+    interned_bytes: usize,
+    /// What the account ids would take if every reference held its own copy.
+    uninterned_bytes: usize,
+    /// Resident memory of the whole process, all networks included.
+    resident_bytes: Option<u64>,
+}
+
+/// Resident set size of the process, only known on Linux.
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[get("/memory")]
+fn memory(state: &NetworkState) -> Json<MemoryReport> {
+    let snapshot = state.snapshot();
+    let interner = state.interner.stats();
+
+    Json(MemoryReport {
+        version: snapshot.version,
+        staking_pools: snapshot.validators.validator_staking_pools.len(),
+        delegators: snapshot.delegators.delegator_staking_pools.len(),
+        positions: snapshot
+            .validators
+            .validator_staking_pools
+            .values()
+            .map(|delegators| delegators.len())
+            .sum(),
+        interned_ids: interner.ids,
+        interned_bytes: interner.bytes,
+        uninterned_bytes: interner.uninterned_bytes,
+        resident_bytes: resident_bytes(),
+    })
+}
+
+/// Tells why a request to a route that changes the index was refused.
+#[catch(401)]
+fn unauthorized(request: &rocket::Request<'_>) -> Json<webhook::WebhookError> {
+    let failure = request.local_cache(auth::AuthFailure::default);
+
+    Json(webhook::WebhookError::new(if failure.0.is_empty() {
+        "Unauthorized"
+    } else {
+        failure.0.as_str()
+    }))
 }
 
 #[tokio::main]
@@ -139,116 +860,46 @@ async fn main() -> Result<()> {
         .filter(None, log::LevelFilter::Info)
         .init();
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-
-    let initial_delegators_state = delegators::get_delegators_from_cache()
-        .await
-        .unwrap_or_default();
-    let initial_validators_state =
-        delegators::ValidatorsWithTimestamp::from(&initial_delegators_state);
-
-    let app_state = AppState {
-        validators_to_process: Arc::new(RwLock::new(BTreeMap::new())),
-        delegators_state: Arc::new(RwLock::new(initial_delegators_state)),
-        validators_state: Arc::new(RwLock::new(initial_validators_state)),
-        tx,
-    };
-    let app_state_clone = app_state.clone();
-
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-
-    tokio::spawn(async move {
-        let beta_json_rpc_client = JsonRpcClient::connect("https://beta.rpc.mainnet.near.org");
-
-        loop {
-            interval.tick().await;
-
-            if chrono::Utc::now().timestamp()
-                - app_state_clone.delegators_state.read().await.timestamp
-                > 1800
-            {
-                let block_reference = near_primitives::types::Finality::Final.into();
-
-                let Ok(block_id) =
-                    methods::get_block_id(&beta_json_rpc_client, block_reference).await
-                else {
-                    error!("Failed to get block id");
-                    continue;
-                };
-
-                let Ok(validators_to_update) =
-                    methods::get_all_validators(&beta_json_rpc_client).await
-                else {
-                    error!("Failed to get all validators");
-                    continue;
-                };
-
-                let mut validators_to_process = app_state_clone.validators_to_process.write().await;
-
-                for validator in validators_to_update {
-                    validators_to_process
-                        .entry(validator)
-                        .and_modify(|prev_block_id| {
-                            if *prev_block_id < block_id {
-                                *prev_block_id = block_id;
-                            }
-                        })
-                        .or_insert(block_id);
-                }
-
-                drop(validators_to_process);
-
-                if app_state_clone.tx.send(()).await.is_err() {
-                    error!("Failed to send message to the worker");
-                }
-            }
-        }
-    });
-
-    let app_state_clone = app_state.clone();
-    tokio::spawn(async move {
-        let json_rpc_client = JsonRpcClient::connect("https://rpc.mainnet.near.org");
-
-        while rx.recv().await.is_some() {
-            let mut validators_to_process = BTreeMap::new();
-            std::mem::swap(
-                &mut *app_state_clone.validators_to_process.write().await,
-                &mut validators_to_process,
-            );
-
-            let mut handles = Vec::new();
-
-            for (account_id, block_id) in validators_to_process {
-                let app_state_clone = app_state_clone.clone();
-                let beta_json_rpc_client = json_rpc_client.clone();
-                handles.push(tokio::spawn(async move {
-                    if let Err(e) = delegators::update_delegators_by_validator_account_id(
-                        &beta_json_rpc_client,
-                        &app_state_clone.delegators_state,
-                        &app_state_clone.validators_state,
-                        account_id.clone(),
-                        block_id,
-                    )
-                    .await
-                    {
-                        error!("Error updating delegators: {}", e);
-                    }
-                }));
-            }
+    let config = config::Config::from_figment(&rocket::Config::figment())?;
 
-            futures::future::join_all(handles).await;
+    let mut networks = BTreeMap::new();
+    for (network, network_config) in config.networks {
+        info!("Starting index for network <{network}>");
+        networks.insert(network, NetworkState::start(network_config).await?);
+    }
 
-            if let Err(e) =
-                delegators::update_delegators_cache(&app_state_clone.delegators_state).await
-            {
-                error!("Error updating delegators cache: {}", e);
-            }
-        }
-    });
+    let routes = routes![
+        get_all,
+        stream_all,
+        get_by_account_id,
+        get_validators,
+        get_validator_delegators,
+        get_validator_delegators_count,
+        get_changes,
+        get_validator_changes,
+        sync,
+        update,
+        get_job,
+        get_resync,
+        get_evictions,
+        rpc_health,
+        memory
+    ];
+
+    let mut rocket = rocket::build();
+    if networks.contains_key(&config::Network::Mainnet) {
+        rocket = rocket.mount("/", routes.clone());
+    }
+    for network in networks.keys() {
+        rocket = rocket.mount(format!("/{network}"), routes.clone());
+    }
 
-    let _ = rocket::build()
-        .mount("/", routes![get_all, get_by_account_id, update])
-        .manage(app_state)
+    let _ = rocket
+        .register("/", catchers![unauthorized])
+        .manage(AppState {
+            networks,
+            authenticator: auth::Authenticator::new(config.auth),
+        })
         .launch()
         .await;
 
@@ -1,25 +1,25 @@
+use crate::delegators::DelegatorStake;
 use crate::extensions::{self, CallResultExt, RpcQueryResponseExt};
+use crate::rpc::RpcPool;
 
 use color_eyre::{eyre::Context, Result};
 
-use near_jsonrpc_client::JsonRpcClient;
-
 use borsh::BorshDeserialize;
 
 use futures::{stream::StreamExt, TryStreamExt};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 pub const ATTEMPTS: u8 = 20;
 pub const LIMIT: usize = 500;
 
 pub async fn get_receiver_id(
-    beta_json_rpc_client: &JsonRpcClient,
+    rpc_pool: &RpcPool,
     receipt_id: String,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     info!("Fetching receipt");
 
     for _ in 0..ATTEMPTS {
-        let Ok(receipt_response) = beta_json_rpc_client
+        let Ok(receipt_response) = rpc_pool
             .call(
                 near_jsonrpc_client::methods::EXPERIMENTAL_receipt::RpcReceiptRequest {
                     receipt_reference: near_jsonrpc_primitives::types::receipts::ReceiptReference {
@@ -45,13 +45,13 @@ pub async fn get_receiver_id(
 }
 
 pub async fn get_block_id(
-    beta_json_rpc_client: &JsonRpcClient,
+    rpc_pool: &RpcPool,
     block_reference: near_primitives::types::BlockReference,
 ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
     info!("Fetching block ID");
 
     for _ in 0..ATTEMPTS {
-        let Ok(block_response) = beta_json_rpc_client
+        let Ok(block_response) = rpc_pool
             .call(near_jsonrpc_client::methods::block::RpcBlockRequest {
                 block_reference: block_reference.clone(),
             })
@@ -72,24 +72,74 @@ pub async fn get_block_id(
     ))
 }
 
-pub async fn get_all_validators(beta_json_rpc_client: &JsonRpcClient) -> Result<BTreeSet<String>> {
+/// Hash of the contract deployed on `account_id`, or `None` if there is no such account.
+pub async fn get_code_hash(
+    rpc_pool: &RpcPool,
+    account_id: &str,
+) -> Result<Option<near_primitives::hash::CryptoHash>> {
+    let response = match rpc_pool
+        .call(near_jsonrpc_client::methods::query::RpcQueryRequest {
+            block_reference: near_primitives::types::Finality::Final.into(),
+            request: near_primitives::views::QueryRequest::ViewAccount {
+                account_id: account_id.parse()?,
+            },
+        })
+        .await
+    {
+        Ok(response) => response,
+        Err(near_jsonrpc_client::errors::JsonRpcError::ServerError(
+            near_jsonrpc_client::errors::JsonRpcServerError::HandlerError(
+                near_jsonrpc_primitives::types::query::RpcQueryError::UnknownAccount { .. },
+            ),
+        )) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to fetch account <{account_id}>")),
+    };
+
+    if let near_jsonrpc_primitives::types::query::QueryResponseKind::ViewAccount(account) =
+        response.kind
+    {
+        Ok(Some(account.code_hash))
+    } else {
+        Err(color_eyre::Report::msg(format!(
+            "Unexpected response to view_account of <{account_id}>"
+        )))
+    }
+}
+
+pub async fn get_all_validators(
+    rpc_pool: &RpcPool,
+    pool_factories: &[String],
+) -> Result<BTreeSet<String>> {
     info!("Fetching all validators");
 
-    let query_view_method_response = beta_json_rpc_client
+    let mut validators = BTreeSet::new();
+
+    for pool_factory in pool_factories {
+        validators.extend(get_validators_by_pool_factory(rpc_pool, pool_factory).await?);
+    }
+
+    Ok(validators)
+}
+
+async fn get_validators_by_pool_factory(
+    rpc_pool: &RpcPool,
+    pool_factory: &str,
+) -> Result<BTreeSet<String>> {
+    let query_view_method_response = rpc_pool
         .call(near_jsonrpc_client::methods::query::RpcQueryRequest {
             block_reference: near_primitives::types::Finality::Final.into(),
             request: near_primitives::views::QueryRequest::ViewState {
-                account_id: "poolv1.near".parse()?,
+                account_id: pool_factory.parse()?,
                 prefix: near_primitives::types::StoreKey::from(Vec::new()),
                 include_proof: false,
             },
         })
         .await
-        .context("Failed to fetch query ViewState for <poolv1.near> on network <beta-rpc>")?;
+        .with_context(|| format!("Failed to fetch query ViewState for <{pool_factory}>"))?;
     if let near_jsonrpc_primitives::types::query::QueryResponseKind::ViewState(result) =
         query_view_method_response.kind
     {
-        info!("Parsing validators");
+        info!("Parsing validators of <{pool_factory}>");
 
         Ok(result
             .values
@@ -112,11 +162,11 @@ pub async fn get_all_validators(beta_json_rpc_client: &JsonRpcClient) -> Result<
 }
 
 async fn get_number_of_delegators(
-    beta_json_rpc_client: &JsonRpcClient,
+    rpc_pool: &RpcPool,
     block_reference: near_primitives::types::BlockReference,
     validator_account_id: String,
 ) -> Result<usize> {
-    let delegators_response = beta_json_rpc_client
+    let delegators_response = rpc_pool
         .call(near_jsonrpc_client::methods::query::RpcQueryRequest {
             block_reference,
             request: near_primitives::views::QueryRequest::CallFunction {
@@ -147,12 +197,12 @@ async fn get_number_of_delegators(
 }
 
 pub async fn get_delegators_by_validator_account_id(
-    beta_json_rpc_client: &JsonRpcClient,
+    rpc_pool: &RpcPool,
     validator_account_id: String,
     block_reference: near_primitives::types::BlockReference,
-) -> Result<BTreeSet<String>> {
+) -> Result<BTreeMap<String, DelegatorStake>> {
     let number_of_delegators = get_number_of_delegators(
-        beta_json_rpc_client,
+        rpc_pool,
         block_reference.clone(),
         validator_account_id.clone(),
     )
@@ -163,7 +213,7 @@ pub async fn get_delegators_by_validator_account_id(
         let validator_account_id = validator_account_id.clone();
 
         async move {
-            let delegators_response = beta_json_rpc_client
+            let delegators_response = rpc_pool
                 .call(near_jsonrpc_client::methods::query::RpcQueryRequest {
                     block_reference: block_reference.clone(),
                     request: near_primitives::views::QueryRequest::CallFunction {
@@ -182,12 +232,12 @@ pub async fn get_delegators_by_validator_account_id(
             match delegators_response {
                 Ok(response) => response
                     .call_result()?
-                    .parse_result_from_json::<BTreeSet<extensions::Delegator>>()
+                    .parse_result_from_json::<Vec<extensions::Delegator>>()
                     .map(|delegators| {
                         delegators
                             .into_iter()
-                            .map(|delegator| delegator.account_id.to_string())
-                            .collect::<BTreeSet<_>>()
+                            .map(|delegator| (delegator.account_id.to_string(), delegator.stake))
+                            .collect::<BTreeMap<_, _>>()
                     })
                     .context("Failed to parse delegators"),
                 Err(near_jsonrpc_client::errors::JsonRpcError::ServerError(
@@ -197,13 +247,13 @@ pub async fn get_delegators_by_validator_account_id(
                             ..
                         },
                     ),
-                )) => Ok(BTreeSet::new()),
+                )) => Ok(BTreeMap::new()),
                 Err(err) => Err(err.into()),
             }
         }
     })
         .buffer_unordered(50)
-        .try_collect::<BTreeSet<_>>()
+        .try_collect::<Vec<_>>()
         .await?;
 
     Ok(delegators.into_iter().flatten().collect())
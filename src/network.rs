@@ -0,0 +1,598 @@
+use crate::{
+    auth::Authenticator,
+    changelog::ChangeLog,
+    config, delegators,
+    evictions::{self, Evictions},
+    follower,
+    history::History,
+    interner::Interner,
+    jobs::Jobs,
+    lake, methods,
+    receipts::ReceiptCache,
+    resync,
+    rpc::RpcPool,
+    snapshot::Snapshot,
+    snapshot::Snapshots,
+    store,
+};
+
+use color_eyre::Result;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    RwLock,
+};
+
+/// Seconds between two full refreshes of the index.
+const FULL_REFRESH_INTERVAL: i64 = 30 * 60;
+/// Seconds before a full resync that could not read every staking pool is attempted again.
+const RESYNC_RETRY_INTERVAL: i64 = 5 * 60;
+/// Seconds between two comparisons of the index with the pool factories, which evict the
+/// staking pools that went missing. Full refreshes compare them as well.
+const RECONCILE_INTERVAL: i64 = 10 * 60;
+
+/// Accounts whose code hash check is remembered, the whole lot is forgotten past it.
+const MAX_CODE_HASH_CHECKS: usize = 10_000;
+/// Seconds before the pool factories are read again for an account named like one of their
+/// staking pools that they did not list.
+const DISCOVERY_RETRY_INTERVAL: i64 = 60;
+
+/// Staking pools listed by the pool factories the last time they were read.
+#[derive(Default)]
+pub struct DiscoveredPools {
+    pub pools: BTreeSet<String>,
+    /// When the pool factories were last read, or about to be.
+    pub read_at: i64,
+}
+
+/// Index of a single network together with the channel to its worker.
+#[derive(Clone)]
+pub struct NetworkState {
+    pub config: Arc<config::NetworkConfig>,
+    pub validators_to_process: Arc<RwLock<BTreeMap<String, u64>>>,
+    /// Copy of the index changed by the worker, requests read [`Self::snapshot`] instead.
+    pub validators_state: Arc<RwLock<delegators::ValidatorsWithTimestamp>>,
+    pub delegators_state: Arc<RwLock<delegators::DelegatorsWithTimestamp>>,
+    /// What requests are answered from, published after every worker batch.
+    pub snapshots: Arc<Snapshots>,
+    pub store: Arc<dyn store::Store>,
+    /// Account ids shared by the index and its snapshots.
+    pub interner: Arc<Interner>,
+    pub change_log: Arc<ChangeLog>,
+    /// Historical snapshots, `None` if they are disabled.
+    pub history: Option<Arc<History>>,
+    /// Webhook calls whose refreshes are tracked.
+    pub jobs: Arc<Jobs>,
+    /// Receipts already received by the webhook.
+    pub receipts: Arc<ReceiptCache>,
+    /// Staking pools missing from the pool factories and the ones evicted for it.
+    pub evictions: Arc<Evictions>,
+    /// Latest full resync, see [`resync::run`].
+    pub resync: Arc<RwLock<Option<resync::ResyncStatus>>>,
+    /// Accounts whose contract was checked against `staking_pool_code_hashes`.
+    pub code_hash_checks: Arc<RwLock<HashMap<String, bool>>>,
+    pub discovered_pools: Arc<std::sync::RwLock<DiscoveredPools>>,
+    pub lookup_rpc_pool: RpcPool,
+    pub query_rpc_pool: RpcPool,
+    pub tx: Sender<()>,
+}
+
+impl NetworkState {
+    /// Loads the cached index of the network and spawns its refresher and worker.
+    pub async fn start(config: config::NetworkConfig) -> Result<Self> {
+        let (network_state, rx) = Self::open(config).await?;
+
+        tokio::spawn(network_state.clone().run_refresher());
+        tokio::spawn(network_state.clone().run_worker(rx));
+        if network_state.history.is_some() {
+            tokio::spawn(network_state.clone().run_historian());
+        }
+        if network_state.config.follower {
+            let source: Arc<dyn follower::BlockSource> = match &network_state.config.lake {
+                Some(lake) => Arc::new(lake::LakeBlockSource::open(lake)?),
+                None => Arc::new(follower::RpcBlockSource::new(
+                    network_state.lookup_rpc_pool.clone(),
+                )),
+            };
+            tokio::spawn(follower::run(
+                network_state.clone(),
+                source,
+                network_state.config.follower_cursor_path.clone(),
+            ));
+        }
+
+        Ok(network_state)
+    }
+
+    /// Loads the cached index of the network without starting anything, returning the
+    /// receiving end of [`NetworkState::tx`] for the worker.
+    pub async fn open(config: config::NetworkConfig) -> Result<(Self, Receiver<()>)> {
+        let lookup_rpc_pool = RpcPool::connect(&config.rpc.lookup)?;
+        let query_rpc_pool = RpcPool::connect(&config.rpc.query)?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+        let store = store::open(&config)?;
+        let interner = Arc::new(Interner::default());
+        let stored = store.load().await.unwrap_or_else(|e| {
+            error!(
+                "[{}] Failed to load the index, starting with an empty one: {:#}",
+                config.network, e
+            );
+            store::StoredIndex::default()
+        });
+        let initial_validators_state = stored.validators.interned(&interner);
+        let change_log = Arc::new(
+            ChangeLog::open(
+                config.changes_path.clone(),
+                config.changes_retention,
+                stored.version,
+            )
+            .await?,
+        );
+
+        let receipts = Arc::new(
+            ReceiptCache::open(config.receipts_path.clone(), config.receipts_retention).await?,
+        );
+
+        let initial_version = change_log.version().await;
+        // Without the delegator -> staking pools direction when lookups by account are
+        // answered from the store, only the metadata of the staking pools is kept.
+        let initial_delegators_state = if config.lookups_from_store {
+            delegators::DelegatorsWithTimestamp {
+                timestamp: initial_validators_state.timestamp,
+                version: Some(initial_version),
+                staking_pools_metadata: initial_validators_state.validators_metadata.clone(),
+                ..delegators::DelegatorsWithTimestamp::default()
+            }
+        } else {
+            delegators::DelegatorsWithTimestamp {
+                version: Some(initial_version),
+                ..delegators::DelegatorsWithTimestamp::from_validators(
+                    &initial_validators_state,
+                    config.min_delegation,
+                )
+            }
+        };
+        let snapshots = Arc::new(Snapshots::new(Snapshot::new(
+            initial_version,
+            initial_validators_state.clone(),
+            initial_delegators_state.clone(),
+        )));
+
+        let evictions = Arc::new(Evictions::open(config.evictions_path.clone()).await?);
+
+        let history = if config.history_interval > 0 {
+            let history =
+                History::open(config.history_dir.clone(), config.history_retention).await?;
+            info!(
+                "[{}] Found {} historical snapshots in <{}>",
+                config.network,
+                history.len().await,
+                config.history_dir.display()
+            );
+            Some(Arc::new(history))
+        } else {
+            None
+        };
+
+        let network_state = Self {
+            config: Arc::new(config),
+            validators_to_process: Arc::new(RwLock::new(BTreeMap::new())),
+            delegators_state: Arc::new(RwLock::new(initial_delegators_state)),
+            validators_state: Arc::new(RwLock::new(initial_validators_state)),
+            snapshots,
+            store,
+            interner,
+            change_log,
+            history,
+            jobs: Arc::new(Jobs::default()),
+            receipts,
+            evictions,
+            resync: Arc::new(RwLock::new(None)),
+            code_hash_checks: Arc::new(RwLock::new(HashMap::new())),
+            discovered_pools: Arc::new(std::sync::RwLock::new(DiscoveredPools::default())),
+            lookup_rpc_pool,
+            query_rpc_pool,
+            tx,
+        };
+
+        Ok((network_state, rx))
+    }
+
+    /// Queues `validator_account_id` to be refreshed at `block_id` unless it is already
+    /// queued at a newer block.
+    pub async fn enqueue(&self, validator_account_id: String, block_id: u64) {
+        self.validators_to_process
+            .write()
+            .await
+            .entry(validator_account_id)
+            .and_modify(|prev_block_id| {
+                if *prev_block_id < block_id {
+                    *prev_block_id = block_id;
+                }
+            })
+            .or_insert(block_id);
+    }
+
+    /// The latest published snapshot of the index.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshots.current()
+    }
+
+    /// Copies the index changed by the worker into a new snapshot for requests to read. Only
+    /// the outer maps are copied: the delegators of every staking pool and the untouched parts
+    /// of the delegator direction are shared with the index and the previous snapshots.
+    ///
+    /// The snapshot is stamped with the version recorded under the locks of the index by the
+    /// last change applied to it, which may come from a resync or an eviction rather than the
+    /// worker, and only published once the change log is synced up to it. Mirrors following
+    /// `/sync` from its version thus neither get a change twice nor miss one.
+    async fn publish_snapshot(&self) -> Result<()> {
+        let validators = self.validators_state.read().await;
+        let delegators = self.delegators_state.read().await;
+        let version = delegators
+            .version
+            .expect("the live index always has a version");
+        let validators = validators.clone();
+        let delegators = delegators.clone();
+
+        // Every change applied to the index was appended before it, so syncing what was
+        // appended so far covers the version.
+        self.change_log.sync().await?;
+        self.snapshots
+            .publish(Snapshot::new(version, validators, delegators));
+
+        Ok(())
+    }
+
+    /// Whether `account_id` is a known staking pool or was listed by the pool factories the
+    /// last time they were read.
+    pub fn is_staking_pool(&self, account_id: &str) -> bool {
+        self.snapshot()
+            .validators
+            .validator_staking_pools
+            .contains_key(account_id)
+            || self
+                .discovered_pools
+                .read()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .pools
+                .contains(account_id)
+    }
+
+    /// Whether `account_id` is named like a staking pool of one of the pool factories, which
+    /// only makes it worth reading their list again.
+    pub fn is_factory_account(&self, account_id: &str) -> bool {
+        account_id
+            .split_once('.')
+            .is_some_and(|(_, parent)| self.config.pool_factories.iter().any(|f| f == parent))
+    }
+
+    /// Replaces the staking pools listed by the pool factories.
+    pub fn set_discovered_pools(&self, pools: BTreeSet<String>) {
+        *self
+            .discovered_pools
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = DiscoveredPools {
+            pools,
+            read_at: chrono::Utc::now().timestamp(),
+        };
+    }
+
+    /// Reads the pool factories again, unless they were read less than
+    /// [`DISCOVERY_RETRY_INTERVAL`] seconds ago. Returns whether they were read.
+    async fn rediscover_pools(&self) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        {
+            let mut discovered = self
+                .discovered_pools
+                .write()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            if now - discovered.read_at < DISCOVERY_RETRY_INTERVAL {
+                return Ok(false);
+            }
+            // Claimed before reading, so that concurrent checks do not read them as well.
+            discovered.read_at = now;
+        }
+
+        let pools =
+            methods::get_all_validators(&self.lookup_rpc_pool, &self.config.pool_factories).await?;
+        self.set_discovered_pools(pools);
+
+        Ok(true)
+    }
+
+    /// Whether `account_id` is a staking pool: a known one, one listed by the pool factories,
+    /// or an account running one of the `staking_pool_code_hashes` contracts. An account named
+    /// like a staking pool of the factories that they did not list yet has them read again.
+    pub async fn verify_staking_pool(&self, account_id: &str) -> Result<bool> {
+        if self.is_staking_pool(account_id) {
+            return Ok(true);
+        }
+        if self.is_factory_account(account_id)
+            && self.rediscover_pools().await?
+            && self.is_staking_pool(account_id)
+        {
+            return Ok(true);
+        }
+        if self.config.staking_pool_code_hashes.is_empty() {
+            return Ok(false);
+        }
+        if let Some(&verified) = self.code_hash_checks.read().await.get(account_id) {
+            return Ok(verified);
+        }
+
+        let verified = self.runs_staking_pool_code(account_id).await?;
+
+        let mut code_hash_checks = self.code_hash_checks.write().await;
+        if code_hash_checks.len() >= MAX_CODE_HASH_CHECKS {
+            code_hash_checks.clear();
+        }
+        code_hash_checks.insert(account_id.to_string(), verified);
+
+        Ok(verified)
+    }
+
+    /// Whether the contract deployed on `account_id` is one of the `staking_pool_code_hashes`,
+    /// checked without the cache.
+    pub async fn runs_staking_pool_code(&self, account_id: &str) -> Result<bool> {
+        if self.config.staking_pool_code_hashes.is_empty() {
+            return Ok(false);
+        }
+
+        Ok(methods::get_code_hash(&self.lookup_rpc_pool, account_id)
+            .await?
+            .is_some_and(|code_hash| self.config.staking_pool_code_hashes.contains(&code_hash)))
+    }
+
+    /// Smallest balance of a position to be served, `include_dust` lowers it to zero.
+    pub fn min_delegation(&self, include_dust: Option<bool>) -> near_primitives::types::Balance {
+        if include_dust.unwrap_or(false) {
+            0
+        } else {
+            self.config.min_delegation
+        }
+    }
+
+    async fn run_refresher(self) {
+        let network = self.config.network;
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        // Refreshes of single staking pools keep the index timestamp recent, so full resyncs
+        // are scheduled on their own. So are evictions, which would otherwise wait for a full
+        // refresh that may never be due.
+        let mut next_resync = self.snapshot().delegators.timestamp + FULL_REFRESH_INTERVAL;
+        let mut next_reconcile = 0;
+
+        loop {
+            interval.tick().await;
+
+            let now = chrono::Utc::now().timestamp();
+            let refresh_due = if self.config.atomic_resync {
+                now >= next_resync
+            } else {
+                now - self.snapshot().delegators.timestamp > FULL_REFRESH_INTERVAL
+            };
+            if !refresh_due && now < next_reconcile {
+                continue;
+            }
+
+            let block_reference = near_primitives::types::Finality::Final.into();
+
+            let Ok(block_id) = methods::get_block_id(&self.lookup_rpc_pool, block_reference).await
+            else {
+                error!("[{network}] Failed to get block id");
+                continue;
+            };
+
+            let Ok(mut validators_to_update) =
+                methods::get_all_validators(&self.lookup_rpc_pool, &self.config.pool_factories)
+                    .await
+            else {
+                error!("[{network}] Failed to get all validators");
+                continue;
+            };
+            self.set_discovered_pools(validators_to_update.clone());
+
+            let evicted = evictions::reconcile(&self, &validators_to_update, block_id).await;
+            next_reconcile = now + RECONCILE_INTERVAL;
+            if !refresh_due {
+                continue;
+            }
+
+            // Pools that are not listed by the factories (or whose balances are unknown after
+            // loading an old cache) are refreshed as well, until they are evicted.
+            validators_to_update.extend(
+                self.snapshot()
+                    .validators
+                    .validator_staking_pools
+                    .keys()
+                    .filter(|validator| !evicted.contains(validator))
+                    .map(ToString::to_string),
+            );
+
+            if self.config.atomic_resync {
+                let status =
+                    resync::run(&self, block_id, validators_to_update.into_iter().collect()).await;
+                next_resync = chrono::Utc::now().timestamp()
+                    + if status.state == resync::ResyncState::Applied {
+                        FULL_REFRESH_INTERVAL
+                    } else {
+                        RESYNC_RETRY_INTERVAL
+                    };
+                continue;
+            }
+
+            for validator in validators_to_update {
+                self.enqueue(validator, block_id).await;
+            }
+
+            if self.tx.send(()).await.is_err() {
+                error!("[{network}] Failed to send message to the worker");
+            }
+        }
+    }
+
+    async fn run_historian(self) {
+        let network = self.config.network;
+        let Some(history) = self.history.clone() else {
+            return;
+        };
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(self.config.history_interval));
+
+        loop {
+            interval.tick().await;
+
+            match history.take_snapshot(&self.snapshot().validators).await {
+                Ok(Some(block_height)) => {
+                    info!("[{network}] Saved historical snapshot at block {block_height}");
+                }
+                Ok(None) => {}
+                Err(e) => error!("[{network}] Error saving historical snapshot: {}", e),
+            }
+        }
+    }
+
+    async fn run_worker(self, mut rx: Receiver<()>) {
+        let network = self.config.network;
+
+        while rx.recv().await.is_some() {
+            let mut validators_to_process = BTreeMap::new();
+            std::mem::swap(
+                &mut *self.validators_to_process.write().await,
+                &mut validators_to_process,
+            );
+
+            let mut handles = Vec::new();
+
+            for (account_id, block_id) in validators_to_process {
+                let network_state = self.clone();
+                handles.push(tokio::spawn(async move {
+                    let result = delegators::update_delegators_by_validator_account_id(
+                        &network_state,
+                        account_id.clone(),
+                        block_id,
+                    )
+                    .await;
+                    if let Err(e) = &result {
+                        error!("[{network}] Error updating delegators: {}", e);
+                    }
+
+                    (account_id, block_id, result.err().map(|e| e.to_string()))
+                }));
+            }
+
+            let mut refreshed: Vec<_> = futures::future::join_all(handles)
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+
+            // Published before the jobs are settled, so that a job that succeeded can be
+            // followed by requests seeing its changes. The changes of the whole batch are
+            // synced at once, outside the locks of the index. If they can't be, nothing is
+            // published and refreshes whose changes may not be durable are reported as failed.
+            if let Err(e) = self.publish_snapshot().await {
+                error!("[{network}] {:#}", e);
+                for (_, _, error) in &mut refreshed {
+                    error.get_or_insert_with(|| format!("{e:#}"));
+                }
+            }
+            self.interner.purge();
+
+            for (account_id, block_id, error) in refreshed {
+                if let Err(e) = self
+                    .receipts
+                    .refreshed(&account_id, block_id, error.is_none())
+                    .await
+                {
+                    error!("[{network}] Failed to save receipts: {:#}", e);
+                }
+                self.jobs.refreshed(&account_id, block_id, error).await;
+            }
+
+            let snapshot = self.snapshot();
+            if let Err(e) = self
+                .store
+                .flush(&snapshot.validators, snapshot.version)
+                .await
+            {
+                error!("[{network}] Error updating delegators cache: {}", e);
+            }
+        }
+    }
+}
+
+/// All indexed networks. The network a request refers to is picked by [`NetworkState`]'s
+/// request guard from the base the route was mounted at.
+pub struct AppState {
+    pub networks: BTreeMap<config::Network, NetworkState>,
+    pub authenticator: Authenticator,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r NetworkState {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(app_state) = request.rocket().state::<AppState>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let Some(route) = request.route() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        // Routes mounted at `/` are aliases of the mainnet ones.
+        let network = match route.uri.base().trim_start_matches('/') {
+            "" => Ok(config::Network::Mainnet),
+            base => base.parse(),
+        };
+
+        match network
+            .ok()
+            .and_then(|network| app_state.networks.get(&network))
+        {
+            Some(network_state) => Outcome::Success(network_state),
+            None => Outcome::Error((Status::NotFound, ())),
+        }
+    }
+}
+
+/// Opens the state of a localnet whose cache is in `cache_dir` and whose RPC servers are at
+/// `rpc_url`, without starting anything. The keys of `localnet` are added to its configuration.
+#[cfg(test)]
+pub async fn test_network_state(
+    cache_dir: &std::path::Path,
+    rpc_url: &str,
+    localnet: serde_json::Value,
+) -> (NetworkState, Receiver<()>) {
+    use rocket::figment::{providers::Serialized, Figment};
+    use serde_json::json;
+
+    let mut localnet_config = json!({
+        "rpc": { "lookup": rpc_url, "query": rpc_url },
+        "pool_factories": ["poolv1.near"],
+    });
+    if let (Some(localnet_config), serde_json::Value::Object(localnet)) =
+        (localnet_config.as_object_mut(), localnet)
+    {
+        localnet_config.extend(localnet);
+    }
+
+    let mut config = config::Config::from_figment(&Figment::from(Serialized::defaults(json!({
+        "delegators": {
+            "networks": ["localnet"],
+            "cache_dir": cache_dir,
+            "localnet": localnet_config,
+        },
+    }))))
+    .unwrap();
+    let network_config = config.networks.remove(&config::Network::Localnet).unwrap();
+
+    NetworkState::open(network_config).await.unwrap()
+}
@@ -0,0 +1,354 @@
+use color_eyre::{eyre::Context, Result};
+use near_primitives::hash::CryptoHash;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// What a receipt delivered to the webhook resolved to.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+pub struct ResolvedReceipt {
+    pub receipt_id: CryptoHash,
+    pub block_hash: CryptoHash,
+    pub receiver_id: String,
+    pub block_height: u64,
+}
+
+/// What to do with a receipt delivered to the webhook.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// The receipt was already refreshed at the same block.
+    Seen(ResolvedReceipt),
+    /// The receipt is being handled by the update of another job, given as `(job id, update
+    /// index)`.
+    InFlight(String, usize),
+    /// The receipt is now handled by the update that claimed it.
+    New,
+}
+
+/// A receipt claimed by a job update, until the refresh of its staking pool is settled.
+struct PendingReceipt {
+    job: (String, usize),
+    resolved: Option<ResolvedReceipt>,
+}
+
+struct ReceiptCacheInner {
+    file: tokio::fs::File,
+    /// Lines in the file, including the ones of receipts that were forgotten since.
+    lines: usize,
+    receipts: HashMap<CryptoHash, ResolvedReceipt>,
+    /// Receipt ids from the oldest to the newest.
+    order: VecDeque<CryptoHash>,
+    /// Receipts being handled, by receipt id and block hash.
+    pending: HashMap<(CryptoHash, CryptoHash), PendingReceipt>,
+}
+
+impl ReceiptCacheInner {
+    fn insert(&mut self, receipt: ResolvedReceipt, retention: usize) {
+        if self
+            .receipts
+            .insert(receipt.receipt_id, receipt.clone())
+            .is_none()
+        {
+            self.order.push_back(receipt.receipt_id);
+        }
+
+        while self.order.len() > retention {
+            if let Some(oldest) = self.order.pop_front() {
+                self.receipts.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Receipts already received by the webhook, so that deliveries repeated by alert services
+/// are recognized and receipts are not looked up with the RPC twice.
+///
+/// A receipt is only remembered once the refresh of its staking pool succeeded. Until then
+/// it is claimed by the job update handling it, which later deliveries follow.
+///
+/// Remembered receipts are kept in an append-only NDJSON file that is compacted once it holds
+/// twice as many lines as the `retention` receipts remembered.
+pub struct ReceiptCache {
+    path: PathBuf,
+    retention: usize,
+    inner: Mutex<ReceiptCacheInner>,
+}
+
+impl ReceiptCache {
+    pub async fn open(path: PathBuf, retention: usize) -> Result<Self> {
+        let retention = retention.max(1);
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read receipts <{}>", path.display()))
+            }
+        };
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open receipts <{}>", path.display()))?;
+
+        // A line cut short by a crash is terminated so that new receipts start on a line of
+        // their own.
+        if !content.is_empty() && !content.ends_with('\n') {
+            file.write_all(b"\n").await?;
+        }
+
+        let mut inner = ReceiptCacheInner {
+            file,
+            lines: 0,
+            receipts: HashMap::new(),
+            order: VecDeque::new(),
+            pending: HashMap::new(),
+        };
+        for line in content.lines() {
+            inner.lines += 1;
+            match serde_json::from_str::<ResolvedReceipt>(line) {
+                Ok(receipt) => inner.insert(receipt, retention),
+                Err(e) => warn!("Skipping unreadable receipt in <{}>: {}", path.display(), e),
+            }
+        }
+
+        Ok(Self {
+            path,
+            retention,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// What `receipt_id` resolved to the last time it was received, in whatever block.
+    pub async fn get(&self, receipt_id: &CryptoHash) -> Option<ResolvedReceipt> {
+        self.inner.lock().await.receipts.get(receipt_id).cloned()
+    }
+
+    /// Claims a receipt delivered in `block_hash` for update `index` of job `job_id`, unless it
+    /// was already refreshed at that block or another update is handling it.
+    pub async fn claim(
+        &self,
+        receipt_id: CryptoHash,
+        block_hash: CryptoHash,
+        job_id: &str,
+        index: usize,
+    ) -> Claim {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(seen) = inner.receipts.get(&receipt_id) {
+            if seen.block_hash == block_hash {
+                return Claim::Seen(seen.clone());
+            }
+        }
+        if let Some(pending) = inner.pending.get(&(receipt_id, block_hash)) {
+            let (job_id, index) = pending.job.clone();
+            return Claim::InFlight(job_id, index);
+        }
+
+        inner.pending.insert(
+            (receipt_id, block_hash),
+            PendingReceipt {
+                job: (job_id.to_string(), index),
+                resolved: None,
+            },
+        );
+        Claim::New
+    }
+
+    /// Records what a claimed receipt resolved to, so that it can be remembered once its
+    /// staking pool is refreshed.
+    pub async fn resolved(&self, receipt: ResolvedReceipt) {
+        if let Some(pending) = self
+            .inner
+            .lock()
+            .await
+            .pending
+            .get_mut(&(receipt.receipt_id, receipt.block_hash))
+        {
+            pending.resolved = Some(receipt);
+        }
+    }
+
+    /// Gives up a claimed receipt that will not be refreshed, so that it is handled again the
+    /// next time it is delivered.
+    pub async fn release(&self, receipt_id: CryptoHash, block_hash: CryptoHash) {
+        self.inner
+            .lock()
+            .await
+            .pending
+            .remove(&(receipt_id, block_hash));
+    }
+
+    /// Settles the claimed receipts of `receiver_id` at `block_height` or an older block, which
+    /// are remembered if the refresh succeeded. A receipt is kept in memory even if it could
+    /// not be written.
+    pub async fn refreshed(
+        &self,
+        receiver_id: &str,
+        block_height: u64,
+        succeeded: bool,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+
+        let mut settled = Vec::new();
+        inner.pending.retain(|_, pending| match &pending.resolved {
+            Some(resolved)
+                if resolved.receiver_id == receiver_id && resolved.block_height <= block_height =>
+            {
+                settled.push(resolved.clone());
+                false
+            }
+            _ => true,
+        });
+        if !succeeded || settled.is_empty() {
+            return Ok(());
+        }
+
+        let count = settled.len();
+        let mut lines = Vec::new();
+        for receipt in settled {
+            serde_json::to_writer(&mut lines, &receipt)?;
+            lines.push(b'\n');
+            inner.insert(receipt, self.retention);
+        }
+
+        inner
+            .file
+            .write_all(&lines)
+            .await
+            .context("Failed to append to the receipts")?;
+        inner.lines += count;
+
+        if inner.lines >= 2 * self.retention {
+            self.compact(&mut inner).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the file with the remembered receipts only.
+    async fn compact(&self, inner: &mut ReceiptCacheInner) -> Result<()> {
+        let mut content = Vec::new();
+        for receipt_id in &inner.order {
+            serde_json::to_writer(&mut content, &inner.receipts[receipt_id])?;
+            content.push(b'\n');
+        }
+
+        let temporary_path = self.path.with_extension("ndjson.tmp");
+        tokio::fs::write(&temporary_path, content)
+            .await
+            .context("Failed to write the compacted receipts")?;
+        tokio::fs::rename(&temporary_path, &self.path)
+            .await
+            .context("Failed to move the compacted receipts in place")?;
+
+        inner.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .context("Failed to reopen the receipts")?;
+        inner.lines = inner.order.len();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> CryptoHash {
+        CryptoHash([byte; 32])
+    }
+
+    fn resolved(receipt: u8, block: u8, receiver_id: &str, block_height: u64) -> ResolvedReceipt {
+        ResolvedReceipt {
+            receipt_id: hash(receipt),
+            block_hash: hash(block),
+            receiver_id: receiver_id.to_string(),
+            block_height,
+        }
+    }
+
+    #[tokio::test]
+    async fn receipts_are_remembered_once_their_refresh_succeeded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("receipts.ndjson");
+        let cache = ReceiptCache::open(path.clone(), 10).await.unwrap();
+        let receipt = resolved(1, 10, "a.poolv1.near", 100);
+
+        assert_eq!(cache.claim(hash(1), hash(10), "job", 0).await, Claim::New);
+        // A delivery while the first one is handled follows it.
+        assert_eq!(
+            cache.claim(hash(1), hash(10), "other", 0).await,
+            Claim::InFlight("job".to_string(), 0)
+        );
+        cache.resolved(receipt.clone()).await;
+
+        // A refresh at an older block or of another staking pool settles nothing.
+        cache.refreshed("a.poolv1.near", 99, true).await.unwrap();
+        cache.refreshed("b.poolv1.near", 100, true).await.unwrap();
+        assert!(matches!(
+            cache.claim(hash(1), hash(10), "other", 0).await,
+            Claim::InFlight(..)
+        ));
+
+        cache.refreshed("a.poolv1.near", 100, true).await.unwrap();
+        assert_eq!(
+            cache.claim(hash(1), hash(10), "other", 0).await,
+            Claim::Seen(receipt.clone())
+        );
+        // The same receipt in another block is handled again.
+        assert_eq!(cache.claim(hash(1), hash(11), "other", 0).await, Claim::New);
+
+        let reopened = ReceiptCache::open(path, 10).await.unwrap();
+        assert_eq!(reopened.get(&hash(1)).await, Some(receipt));
+    }
+
+    #[tokio::test]
+    async fn failed_and_released_receipts_are_handled_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ReceiptCache::open(dir.path().join("receipts.ndjson"), 10)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.claim(hash(1), hash(10), "job", 0).await, Claim::New);
+        cache.resolved(resolved(1, 10, "a.poolv1.near", 100)).await;
+        cache.refreshed("a.poolv1.near", 100, false).await.unwrap();
+        assert_eq!(cache.get(&hash(1)).await, None);
+        assert_eq!(cache.claim(hash(1), hash(10), "job", 1).await, Claim::New);
+
+        cache.release(hash(1), hash(10)).await;
+        assert_eq!(cache.claim(hash(1), hash(10), "job", 2).await, Claim::New);
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_receipts_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("receipts.ndjson");
+        let cache = ReceiptCache::open(path.clone(), 2).await.unwrap();
+
+        for receipt in 1..=5 {
+            cache.claim(hash(receipt), hash(10), "job", 0).await;
+            cache
+                .resolved(resolved(receipt, 10, "a.poolv1.near", 100))
+                .await;
+            cache.refreshed("a.poolv1.near", 100, true).await.unwrap();
+        }
+
+        assert_eq!(cache.get(&hash(3)).await, None);
+        assert!(cache.get(&hash(4)).await.is_some());
+        // The file was compacted along the way.
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.lines().count() < 4);
+
+        let reopened = ReceiptCache::open(path, 2).await.unwrap();
+        assert_eq!(reopened.get(&hash(3)).await, None);
+        assert!(reopened.get(&hash(4)).await.is_some());
+        assert!(reopened.get(&hash(5)).await.is_some());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::delegators;
+use crate::interner::Id;
+use crate::network::NetworkState;
+
+use futures::StreamExt;
+use std::collections::BTreeMap;
+
+/// Staking pools read at the same time during a full resync.
+const CONCURRENCY: usize = 10;
+
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ResyncState {
+    Running,
+    /// Every staking pool was read and the result replaced the index.
+    Applied,
+    /// Some staking pools could not be read, or their changes could not be recorded. The index
+    /// was left as it was.
+    Failed,
+}
+
+/// Progress of the latest full resync of a network.
+#[derive(Debug, serde::Serialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ResyncStatus {
+    pub block_height: u64,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub state: ResyncState,
+    /// Staking pools to read at `block_height`.
+    pub staking_pools: usize,
+    /// Staking pools that could not be read, with the error.
+    pub failed: BTreeMap<String, String>,
+    /// Staking pools left as they were, as they had been refreshed at a newer block while the
+    /// resync was running.
+    pub newer: Vec<Id>,
+}
+
+/// Reads every staking pool at `block_height` into a fresh index next to the served one, and
+/// swaps it in only once all of them were read, so that the index never mixes the old and the
+/// new state of a full refresh. Refreshes of a single staking pool go on meanwhile.
+pub async fn run(
+    network_state: &NetworkState,
+    block_height: u64,
+    staking_pools: Vec<String>,
+) -> ResyncStatus {
+    let network = network_state.config.network;
+    let mut status = ResyncStatus {
+        block_height,
+        started_at: chrono::Utc::now().timestamp(),
+        finished_at: None,
+        state: ResyncState::Running,
+        staking_pools: staking_pools.len(),
+        failed: BTreeMap::new(),
+        newer: Vec::new(),
+    };
+    *network_state.resync.write().await = Some(status.clone());
+
+    info!(
+        "[{network}] Resyncing {} staking pools at block {block_height}",
+        staking_pools.len()
+    );
+
+    let results = futures::stream::iter(staking_pools)
+        .map(|staking_pool| async move {
+            let result =
+                delegators::fetch_delegators(network_state, &staking_pool, block_height).await;
+            (staking_pool, result)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut resynced = BTreeMap::new();
+    for (staking_pool, result) in results {
+        match result {
+            Ok(delegators) => {
+                resynced.insert(network_state.interner.intern(&staking_pool), delegators);
+            }
+            Err(e) => {
+                status.failed.insert(staking_pool, format!("{e:#}"));
+            }
+        }
+    }
+
+    if !status.failed.is_empty() {
+        error!(
+            "[{network}] Resync at block {block_height} abandoned, failed staking pools: {}",
+            status
+                .failed
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        status.state = ResyncState::Failed;
+    } else {
+        match delegators::apply_resync(network_state, block_height, resynced).await {
+            Ok((applied, newer)) => {
+                info!(
+                    "[{network}] Resynced {} staking pools at block {block_height}, {} were newer",
+                    applied.len(),
+                    newer.len()
+                );
+                status.state = ResyncState::Applied;
+                status.newer = newer;
+
+                // The worker publishes the new snapshot and flushes the store.
+                if network_state.tx.send(()).await.is_err() {
+                    error!("[{network}] Failed to send message to the worker");
+                }
+            }
+            Err(e) => {
+                error!("[{network}] Resync at block {block_height} abandoned: {e:#}");
+                status.state = ResyncState::Failed;
+            }
+        }
+    }
+
+    status.finished_at = Some(chrono::Utc::now().timestamp());
+    *network_state.resync.write().await = Some(status.clone());
+
+    status
+}
@@ -0,0 +1,154 @@
+use crate::config;
+
+use color_eyre::Result;
+use near_jsonrpc_client::errors::{JsonRpcError, JsonRpcServerError};
+use near_jsonrpc_client::{methods::RpcMethod, JsonRpcClient, MethodCallResult};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of consecutive failures after which an endpoint is ejected from the pool.
+pub const MAX_CONSECUTIVE_ERRORS: u32 = 3;
+/// How long an ejected endpoint stays out of rotation.
+pub const COOLDOWN: Duration = Duration::from_secs(30);
+/// Weight of the latest observation in the moving averages.
+const EWMA_ALPHA: f64 = 0.2;
+
+#[derive(Debug, Default, Clone, serde::Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EndpointHealth {
+    pub url: String,
+    pub latency_ms: f64,
+    pub error_rate: f64,
+    pub consecutive_errors: u32,
+    /// Whether the endpoint is out of rotation at the time of the report.
+    pub ejected: bool,
+    #[serde(skip)]
+    pub ejected_until: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+
+    /// Lower is better: latency penalized by the recent error rate.
+    fn score(&self) -> f64 {
+        self.latency_ms * 10.0f64.mul_add(self.error_rate, 1.0)
+    }
+
+    fn record_success(&mut self, latency: Duration) {
+        self.latency_ms += EWMA_ALPHA * (latency.as_secs_f64() * 1000.0 - self.latency_ms);
+        self.error_rate -= EWMA_ALPHA * self.error_rate;
+        self.consecutive_errors = 0;
+        self.ejected_until = None;
+    }
+
+    fn record_failure(&mut self, latency: Duration) {
+        self.latency_ms += EWMA_ALPHA * (latency.as_secs_f64() * 1000.0 - self.latency_ms);
+        self.error_rate += EWMA_ALPHA * (1.0 - self.error_rate);
+        self.consecutive_errors += 1;
+
+        if self.consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+            warn!(
+                "Ejecting RPC endpoint <{}> for {}s after {} consecutive errors",
+                self.url,
+                COOLDOWN.as_secs(),
+                self.consecutive_errors
+            );
+            self.ejected_until = Some(Instant::now() + COOLDOWN);
+            self.consecutive_errors = 0;
+        }
+    }
+}
+
+struct PooledClient {
+    json_rpc_client: JsonRpcClient,
+    health: Mutex<EndpointHealth>,
+}
+
+/// Set of interchangeable RPC endpoints. Every call goes to the healthiest endpoint,
+/// and endpoints that keep failing are ejected for [`COOLDOWN`].
+#[derive(Clone)]
+pub struct RpcPool {
+    clients: Arc<[PooledClient]>,
+}
+
+impl RpcPool {
+    pub fn connect(endpoints: &[config::RpcEndpoint]) -> Result<Self> {
+        if endpoints.is_empty() {
+            color_eyre::eyre::bail!("RPC pool requires at least one endpoint");
+        }
+
+        let clients = endpoints
+            .iter()
+            .map(|endpoint| {
+                Ok(PooledClient {
+                    json_rpc_client: endpoint.connect()?,
+                    health: Mutex::new(EndpointHealth {
+                        url: endpoint.url.clone(),
+                        ..EndpointHealth::default()
+                    }),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            clients: clients.into(),
+        })
+    }
+
+    /// Picks the endpoint with the best score among those that are not ejected. When every
+    /// endpoint is ejected, the one that comes back first is used.
+    fn pick(&self) -> &PooledClient {
+        let now = Instant::now();
+
+        self.clients
+            .iter()
+            .map(|client| (client, client.health.lock().expect("poisoned").clone()))
+            .min_by(
+                |(_, a), (_, b)| match (a.is_ejected(now), b.is_ejected(now)) {
+                    (false, false) => a.score().total_cmp(&b.score()),
+                    (true, true) => a.ejected_until.cmp(&b.ejected_until),
+                    (a_is_ejected, b_is_ejected) => a_is_ejected.cmp(&b_is_ejected),
+                },
+            )
+            .map(|(client, _)| client)
+            .expect("RPC pool is never empty")
+    }
+
+    pub async fn call<M>(&self, method: M) -> MethodCallResult<M::Response, M::Error>
+    where
+        M: RpcMethod,
+    {
+        let client = self.pick();
+
+        let started_at = Instant::now();
+        let result = client.json_rpc_client.call(method).await;
+        let latency = started_at.elapsed();
+
+        let mut health = client.health.lock().expect("poisoned");
+        match &result {
+            // Handler errors are valid answers about the request, not about the endpoint.
+            Ok(_) | Err(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(_))) => {
+                health.record_success(latency);
+            }
+            Err(_) => health.record_failure(latency),
+        }
+
+        result
+    }
+
+    pub fn health(&self) -> Vec<EndpointHealth> {
+        let now = Instant::now();
+
+        self.clients
+            .iter()
+            .map(|client| {
+                let mut health = client.health.lock().expect("poisoned").clone();
+                health.ejected = health.is_ejected(now);
+                health
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::delegators::{DelegatorsWithTimestamp, ValidatorsWithTimestamp};
+
+use arc_swap::ArcSwap;
+use std::sync::{Arc, OnceLock};
+
+/// Immutable copy of a network's index that requests are answered from.
+///
+/// The worker keeps changing its own copy of the index and publishes a new snapshot once a
+/// batch is done, so requests never wait for it and every answer is read from a single
+/// version of the index.
+pub struct Snapshot {
+    /// Version of the change log the snapshot was taken at, see
+    /// [`crate::changelog::ChangeLog`].
+    pub version: u64,
+    pub validators: ValidatorsWithTimestamp,
+    pub delegators: DelegatorsWithTimestamp,
+    /// Whole `/get-staking-pools` dump, serialized by the first request that needs it.
+    full_dump: OnceLock<Arc<str>>,
+}
+
+impl Snapshot {
+    pub fn new(
+        version: u64,
+        validators: ValidatorsWithTimestamp,
+        delegators: DelegatorsWithTimestamp,
+    ) -> Self {
+        Self {
+            version,
+            validators,
+            delegators: DelegatorsWithTimestamp {
+                version: Some(version),
+                ..delegators
+            },
+            full_dump: OnceLock::new(),
+        }
+    }
+
+    /// The delegators serialized as a whole, computed once per snapshot.
+    pub fn full_dump(&self) -> serde_json::Result<Arc<str>> {
+        if let Some(json) = self.full_dump.get() {
+            return Ok(Arc::clone(json));
+        }
+
+        // Concurrent first requests may serialize it more than once, only one is kept.
+        let json = Arc::from(serde_json::to_string(&self.delegators)?);
+        Ok(Arc::clone(self.full_dump.get_or_init(|| json)))
+    }
+}
+
+/// The latest published [`Snapshot`], replaced atomically.
+pub struct Snapshots(ArcSwap<Snapshot>);
+
+impl Snapshots {
+    pub fn new(snapshot: Snapshot) -> Self {
+        Self(ArcSwap::from_pointee(snapshot))
+    }
+
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.0.load_full()
+    }
+
+    pub fn publish(&self, snapshot: Snapshot) {
+        self.0.store(Arc::new(snapshot));
+    }
+}
@@ -0,0 +1,215 @@
+use super::{Store, StoredIndex};
+use crate::delegators::{DelegatorsWithTimestamp, ValidatorsWithTimestamp};
+
+use color_eyre::{eyre::Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The whole index as a single JSON file, rewritten after every worker batch.
+pub struct JsonFileStore {
+    cache_path: PathBuf,
+    snapshots: usize,
+}
+
+impl JsonFileStore {
+    pub const fn new(cache_path: PathBuf, snapshots: usize) -> Self {
+        Self {
+            cache_path,
+            snapshots,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Store for JsonFileStore {
+    async fn load(&self) -> Result<StoredIndex> {
+        Ok(get_validators_from_cache(&self.cache_path, self.snapshots).await)
+    }
+
+    async fn flush(&self, validators: &ValidatorsWithTimestamp, version: u64) -> Result<()> {
+        update_delegators_cache(&self.cache_path, self.snapshots, validators, version).await
+    }
+}
+
+/// Version of the cache file layout. Files without a header are the unversioned layout that
+/// held `DelegatorsWithTimestamp` or `ValidatorsWithTimestamp` as plain JSON.
+const CACHE_VERSION: u32 = 2;
+
+/// First line of the cache file, followed by the JSON body it describes.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CacheHeader {
+    version: u32,
+    /// SHA-256 of the body.
+    checksum: String,
+    length: usize,
+    /// Version of the change log the index was written at, absent in caches written before
+    /// it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_version: Option<u64>,
+}
+
+/// `cache_path` itself for `0`, otherwise the `index`-th previous snapshot.
+fn snapshot_path(cache_path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        cache_path.to_path_buf()
+    } else {
+        PathBuf::from(format!("{}.{index}", cache_path.display()))
+    }
+}
+
+fn decode_cache(content: &[u8]) -> Result<StoredIndex> {
+    let (header, body) = content
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .map_or((content, &[][..]), |position| {
+            (&content[..position], &content[position + 1..])
+        });
+
+    let Ok(header) = serde_json::from_slice::<CacheHeader>(header) else {
+        if let Ok(validators) = serde_json::from_slice::<ValidatorsWithTimestamp>(content) {
+            return Ok(StoredIndex {
+                validators,
+                version: None,
+            });
+        }
+
+        let delegators = serde_json::from_slice::<DelegatorsWithTimestamp>(content)
+            .context("Failed to parse unversioned cache")?;
+        info!("Loaded cache without balances, scheduling a full refresh");
+
+        return Ok(StoredIndex {
+            validators: ValidatorsWithTimestamp::from(&delegators),
+            version: None,
+        });
+    };
+
+    if header.version != CACHE_VERSION {
+        color_eyre::eyre::bail!("Unsupported cache version {}", header.version);
+    }
+    if header.length != body.len() {
+        color_eyre::eyre::bail!(
+            "Cache is truncated: expected {} bytes, found {}",
+            header.length,
+            body.len()
+        );
+    }
+    if near_primitives::hash::hash(body).to_string() != header.checksum {
+        color_eyre::eyre::bail!("Cache checksum mismatch");
+    }
+
+    Ok(StoredIndex {
+        validators: serde_json::from_slice(body).context("Failed to parse cache")?,
+        version: header.index_version,
+    })
+}
+
+/// Loads the newest cache snapshot that passes the integrity check, trying `cache_path` first
+/// and then up to `snapshots` previous ones. Starts with an empty index if none is usable.
+async fn get_validators_from_cache(cache_path: &Path, snapshots: usize) -> StoredIndex {
+    let mut found_invalid = false;
+
+    for index in 0..=snapshots {
+        let path = snapshot_path(cache_path, index);
+
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) if !content.is_empty() => content,
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                error!("Failed to read cache <{}>: {}", path.display(), e);
+                found_invalid = true;
+                continue;
+            }
+        };
+
+        match decode_cache(&content) {
+            Ok(stored) => {
+                if found_invalid {
+                    error!(
+                        "Newer cache is corrupted, fell back to snapshot <{}> from {}",
+                        path.display(),
+                        stored.validators.timestamp
+                    );
+                }
+                return stored;
+            }
+            Err(e) => {
+                error!("Cache <{}> is corrupted: {:#}", path.display(), e);
+                found_invalid = true;
+            }
+        }
+    }
+
+    if found_invalid {
+        error!("No valid cache found, starting with an empty index");
+    } else {
+        info!("No cache found");
+    }
+
+    StoredIndex::default()
+}
+
+/// Writes the cache to a temporary file and renames it over `cache_path` once it is synced, so
+/// a crash never leaves a partially written cache behind. The replaced cache is kept as the
+/// newest of `snapshots` previous snapshots.
+async fn update_delegators_cache(
+    cache_path: &Path,
+    snapshots: usize,
+    validators_with_timestamp: &ValidatorsWithTimestamp,
+    index_version: u64,
+) -> Result<()> {
+    let body = serde_json::to_vec_pretty(validators_with_timestamp)?;
+    let header = serde_json::to_vec(&CacheHeader {
+        version: CACHE_VERSION,
+        checksum: near_primitives::hash::hash(&body).to_string(),
+        length: body.len(),
+        index_version: Some(index_version),
+    })?;
+
+    let temporary_path = PathBuf::from(format!("{}.tmp", cache_path.display()));
+    if let Err(e) = write_synced(&temporary_path, &[&header, b"\n", &body]).await {
+        let _ = tokio::fs::remove_file(&temporary_path).await;
+        return Err(e);
+    }
+
+    for index in (0..snapshots).rev() {
+        let from = snapshot_path(cache_path, index);
+        if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+            tokio::fs::rename(&from, snapshot_path(cache_path, index + 1))
+                .await
+                .context("Failed to rotate cache snapshots")?;
+        }
+    }
+
+    tokio::fs::rename(&temporary_path, cache_path)
+        .await
+        .context("Failed to replace the cache file")?;
+
+    if let Some(directory) = cache_path.parent() {
+        tokio::fs::File::open(directory)
+            .await
+            .context("Failed to open the cache directory")?
+            .sync_all()
+            .await
+            .context("Failed to sync the cache directory")?;
+    }
+
+    info!("Updated delegators file");
+
+    Ok(())
+}
+
+async fn write_synced(path: &Path, parts: &[&[u8]]) -> Result<()> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .context("Failed to create file")?;
+
+    for part in parts {
+        file.write_all(part)
+            .await
+            .context("Failed to write to file")?;
+    }
+
+    file.sync_all().await.context("Failed to sync file")
+}
@@ -0,0 +1,51 @@
+mod json;
+mod sqlite;
+
+pub use json::JsonFileStore;
+pub use sqlite::SqliteStore;
+
+use crate::config;
+use crate::delegators::{DelegatorStake, ValidatorsWithTimestamp};
+use crate::interner::Id;
+
+use color_eyre::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Index read back by [`Store::load`].
+#[derive(Debug, Default)]
+pub struct StoredIndex {
+    pub validators: ValidatorsWithTimestamp,
+    /// Version of the change log the index was flushed at, if the backend keeps track of it.
+    pub version: Option<u64>,
+}
+
+/// Persistence of a network's index.
+///
+/// The worker calls [`Store::flush`] once a batch is done with the whole published index and
+/// its version, so backends can either write the pools that changed or snapshot everything.
+/// Rows and version are written together, so a crash never leaves one without the other.
+#[rocket::async_trait]
+pub trait Store: Send + Sync {
+    /// Reads the persisted index, or an empty one if there is nothing usable.
+    async fn load(&self) -> Result<StoredIndex>;
+
+    /// Persists the index as published at `version` of the change log.
+    async fn flush(&self, validators: &ValidatorsWithTimestamp, version: u64) -> Result<()>;
+
+    /// Positions of `account_id` in every staking pool as of the last flush, read straight
+    /// from the store, or `None` if the backend can only answer from the in-memory index.
+    async fn lookup(&self, _account_id: &str) -> Result<Option<BTreeMap<Id, DelegatorStake>>> {
+        Ok(None)
+    }
+}
+
+pub fn open(config: &config::NetworkConfig) -> Result<Arc<dyn Store>> {
+    Ok(match config.store {
+        config::StoreKind::Json => Arc::new(JsonFileStore::new(
+            config.cache_path.clone(),
+            config.cache_snapshots,
+        )),
+        config::StoreKind::Sqlite => Arc::new(SqliteStore::open(&config.cache_path)?),
+    })
+}
@@ -0,0 +1,494 @@
+use super::{Store, StoredIndex};
+use crate::delegators::{DelegatorStake, ValidatorMetadata, ValidatorsWithTimestamp};
+use crate::interner::Id;
+
+use color_eyre::{eyre::Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS validators (
+        account_id TEXT PRIMARY KEY,
+        block_height INTEGER,
+        timestamp INTEGER,
+        last_failure TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS delegations (
+        validator_account_id TEXT NOT NULL,
+        delegator_account_id TEXT NOT NULL,
+        staked_balance TEXT NOT NULL,
+        unstaked_balance TEXT NOT NULL,
+        can_withdraw INTEGER NOT NULL,
+        PRIMARY KEY (validator_account_id, delegator_account_id)
+    ) WITHOUT ROWID;
+
+    CREATE INDEX IF NOT EXISTS delegations_by_delegator
+        ON delegations (delegator_account_id);
+";
+
+/// Delegator and validator rows in an embedded SQLite database. Every flush only rewrites the
+/// rows of the staking pools that changed since the previous one, and lookups by account are
+/// answered from disk.
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+    /// Metadata of every staking pool as last written, to find the ones that changed.
+    flushed: tokio::sync::Mutex<BTreeMap<Id, ValidatorMetadata>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite store <{}>", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("Failed to create SQLite schema")?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            flushed: tokio::sync::Mutex::default(),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().expect("poisoned")))
+            .await
+            .context("SQLite task panicked")?
+    }
+}
+
+fn parse_balance(value: &str) -> Result<near_primitives::types::Balance> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid balance <{value}> in SQLite store"))
+}
+
+fn upsert_metadata(
+    connection: &Connection,
+    validator_account_id: &str,
+    metadata: &ValidatorMetadata,
+) -> Result<()> {
+    let last_failure = metadata
+        .last_failure
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()?;
+
+    connection.execute(
+        "INSERT INTO validators (account_id, block_height, timestamp, last_failure)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (account_id) DO UPDATE SET
+                block_height = excluded.block_height,
+                timestamp = excluded.timestamp,
+                last_failure = excluded.last_failure",
+        params![
+            validator_account_id,
+            metadata.block_height,
+            metadata.timestamp,
+            last_failure
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn replace_delegators(
+    connection: &Connection,
+    validator_account_id: &str,
+    delegators: &BTreeMap<Id, DelegatorStake>,
+) -> Result<()> {
+    let stored_delegators = connection
+        .prepare("SELECT delegator_account_id FROM delegations WHERE validator_account_id = ?1")?
+        .query_map([validator_account_id], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<BTreeSet<_>>>()?;
+
+    let mut delete = connection.prepare(
+        "DELETE FROM delegations WHERE validator_account_id = ?1 AND delegator_account_id = ?2",
+    )?;
+    for delegator in stored_delegators
+        .iter()
+        .filter(|delegator| !delegators.contains_key(delegator.as_str()))
+    {
+        delete.execute(params![validator_account_id, delegator])?;
+    }
+
+    // Only rows whose stake changed are actually rewritten.
+    let mut upsert = connection.prepare(
+        "INSERT INTO delegations (validator_account_id, delegator_account_id,
+                staked_balance, unstaked_balance, can_withdraw)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (validator_account_id, delegator_account_id) DO UPDATE SET
+                staked_balance = excluded.staked_balance,
+                unstaked_balance = excluded.unstaked_balance,
+                can_withdraw = excluded.can_withdraw
+            WHERE staked_balance != excluded.staked_balance
+                OR unstaked_balance != excluded.unstaked_balance
+                OR can_withdraw != excluded.can_withdraw",
+    )?;
+    for (delegator, stake) in delegators {
+        upsert.execute(params![
+            validator_account_id,
+            delegator,
+            stake.staked_balance.to_string(),
+            stake.unstaked_balance.to_string(),
+            stake.can_withdraw
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn remove_validator(connection: &Connection, validator_account_id: &str) -> Result<()> {
+    connection.execute(
+        "DELETE FROM delegations WHERE validator_account_id = ?1",
+        [validator_account_id],
+    )?;
+    connection.execute(
+        "DELETE FROM validators WHERE account_id = ?1",
+        [validator_account_id],
+    )?;
+
+    Ok(())
+}
+
+fn upsert_meta(connection: &Connection, key: &str, value: i64) -> Result<()> {
+    connection.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+
+    Ok(())
+}
+
+/// Rows of a staking pool that changed since the previous flush.
+struct ChangedPool {
+    validator_account_id: Id,
+    metadata: ValidatorMetadata,
+    /// `None` if only the metadata changed, e.g. after a failed refresh.
+    delegators: Option<Arc<BTreeMap<Id, DelegatorStake>>>,
+}
+
+#[rocket::async_trait]
+impl Store for SqliteStore {
+    async fn load(&self) -> Result<StoredIndex> {
+        let stored = self
+            .with_connection(|connection| {
+                let mut validators = ValidatorsWithTimestamp {
+                    timestamp: connection
+                        .query_row(
+                            "SELECT value FROM meta WHERE key = 'timestamp'",
+                            [],
+                            |row| row.get(0),
+                        )
+                        .optional()?
+                        .unwrap_or_default(),
+                    ..ValidatorsWithTimestamp::default()
+                };
+
+                let mut statement = connection.prepare(
+                    "SELECT account_id, block_height, timestamp, last_failure FROM validators",
+                )?;
+                let mut rows = statement.query([])?;
+                while let Some(row) = rows.next()? {
+                    let last_failure = row
+                        .get::<_, Option<String>>(3)?
+                        .map(|last_failure| serde_json::from_str(&last_failure))
+                        .transpose()?;
+
+                    validators.validators_metadata.insert(
+                        Id::from(row.get::<_, String>(0)?),
+                        ValidatorMetadata {
+                            block_height: row.get(1)?,
+                            timestamp: row.get(2)?,
+                            last_failure,
+                        },
+                    );
+                }
+
+                let mut statement = connection.prepare(
+                    "SELECT validator_account_id, delegator_account_id, staked_balance,
+                    unstaked_balance, can_withdraw FROM delegations",
+                )?;
+                let mut rows = statement.query([])?;
+                while let Some(row) = rows.next()? {
+                    Arc::make_mut(
+                        validators
+                            .validator_staking_pools
+                            .entry(Id::from(row.get::<_, String>(0)?))
+                            .or_default(),
+                    )
+                    .insert(
+                        Id::from(row.get::<_, String>(1)?),
+                        DelegatorStake {
+                            staked_balance: parse_balance(&row.get::<_, String>(2)?)?,
+                            unstaked_balance: parse_balance(&row.get::<_, String>(3)?)?,
+                            can_withdraw: row.get(4)?,
+                        },
+                    );
+                }
+
+                let version = connection
+                    .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .optional()?
+                    .map(u64::try_from)
+                    .transpose()?;
+
+                Ok(StoredIndex {
+                    validators,
+                    version,
+                })
+            })
+            .await?;
+
+        self.flushed
+            .lock()
+            .await
+            .clone_from(&stored.validators.validators_metadata);
+
+        Ok(stored)
+    }
+
+    /// Writes the staking pools whose metadata changed since the previous flush in a single
+    /// transaction with `version`, then folds the write-ahead log into the database.
+    async fn flush(&self, validators: &ValidatorsWithTimestamp, version: u64) -> Result<()> {
+        let mut flushed = self.flushed.lock().await;
+
+        let pools = validators
+            .validators_metadata
+            .keys()
+            .chain(validators.validator_staking_pools.keys())
+            .collect::<BTreeSet<_>>();
+
+        let mut changed = Vec::new();
+        for validator_account_id in &pools {
+            let metadata = validators
+                .validators_metadata
+                .get(*validator_account_id)
+                .cloned()
+                .unwrap_or_default();
+            let previous = flushed.get(*validator_account_id);
+            if previous == Some(&metadata) {
+                continue;
+            }
+
+            let refreshed = previous.is_none_or(|previous| {
+                (previous.block_height, previous.timestamp)
+                    != (metadata.block_height, metadata.timestamp)
+            });
+            changed.push(ChangedPool {
+                validator_account_id: (*validator_account_id).clone(),
+                metadata,
+                delegators: refreshed.then(|| {
+                    validators
+                        .validator_staking_pools
+                        .get(*validator_account_id)
+                        .cloned()
+                        .unwrap_or_default()
+                }),
+            });
+        }
+
+        let removed = flushed
+            .keys()
+            .filter(|validator_account_id| !pools.contains(validator_account_id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let timestamp = validators.timestamp;
+        let version = i64::try_from(version)?;
+        let changed = self
+            .with_connection(move |connection| {
+                let transaction = connection.transaction()?;
+
+                for pool in &changed {
+                    if let Some(delegators) = &pool.delegators {
+                        replace_delegators(&transaction, &pool.validator_account_id, delegators)?;
+                    }
+                    upsert_metadata(&transaction, &pool.validator_account_id, &pool.metadata)?;
+                }
+                for validator_account_id in &removed {
+                    remove_validator(&transaction, validator_account_id)?;
+                }
+                upsert_meta(&transaction, "timestamp", timestamp)?;
+                upsert_meta(&transaction, "version", version)?;
+
+                transaction.commit()?;
+                connection.execute_batch("PRAGMA wal_checkpoint(PASSIVE);")?;
+
+                Ok((changed, removed))
+            })
+            .await?;
+
+        let (changed, removed) = changed;
+        for validator_account_id in removed {
+            flushed.remove(&validator_account_id);
+        }
+        for pool in changed {
+            flushed.insert(pool.validator_account_id, pool.metadata);
+        }
+
+        Ok(())
+    }
+
+    async fn lookup(&self, account_id: &str) -> Result<Option<BTreeMap<Id, DelegatorStake>>> {
+        let account_id = account_id.to_string();
+
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT validator_account_id, staked_balance, unstaked_balance, can_withdraw
+                    FROM delegations WHERE delegator_account_id = ?1",
+            )?;
+            let mut rows = statement.query([&account_id])?;
+
+            let mut stakes = BTreeMap::new();
+            while let Some(row) = rows.next()? {
+                stakes.insert(
+                    Id::from(row.get::<_, String>(0)?),
+                    DelegatorStake {
+                        staked_balance: parse_balance(&row.get::<_, String>(1)?)?,
+                        unstaked_balance: parse_balance(&row.get::<_, String>(2)?)?,
+                        can_withdraw: row.get(3)?,
+                    },
+                );
+            }
+
+            Ok(Some(stakes))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stake(staked_balance: u128) -> DelegatorStake {
+        DelegatorStake {
+            staked_balance,
+            unstaked_balance: 0,
+            can_withdraw: true,
+        }
+    }
+
+    fn refreshed(
+        validators: &mut ValidatorsWithTimestamp,
+        staking_pool: &str,
+        block_height: u64,
+        delegators: &[(&str, u128)],
+    ) {
+        validators.validator_staking_pools.insert(
+            Id::from(staking_pool),
+            Arc::new(
+                delegators
+                    .iter()
+                    .map(|(delegator, staked_balance)| {
+                        (Id::from(*delegator), stake(*staked_balance))
+                    })
+                    .collect(),
+            ),
+        );
+        validators.validators_metadata.insert(
+            Id::from(staking_pool),
+            ValidatorMetadata {
+                block_height: Some(block_height),
+                timestamp: Some(block_height as i64),
+                last_failure: None,
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn flushed_rows_are_loaded_back_with_their_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delegators.sqlite");
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.load().await.unwrap().version, None);
+
+        let mut validators = ValidatorsWithTimestamp::default();
+        refreshed(&mut validators, "a.poolv1.near", 100, &[("alice.near", 10)]);
+        refreshed(&mut validators, "b.poolv1.near", 100, &[("bob.near", 20)]);
+        store.flush(&validators, 2).await.unwrap();
+
+        // One pool changes and the other one is evicted.
+        refreshed(
+            &mut validators,
+            "a.poolv1.near",
+            101,
+            &[("alice.near", 15), ("carol.near", 30)],
+        );
+        validators.validator_staking_pools.remove("b.poolv1.near");
+        validators.validators_metadata.remove("b.poolv1.near");
+        validators.timestamp = 101;
+        store.flush(&validators, 4).await.unwrap();
+        drop(store);
+
+        let stored = SqliteStore::open(&path).unwrap().load().await.unwrap();
+        assert_eq!(stored.version, Some(4));
+        assert_eq!(stored.validators.timestamp, 101);
+        assert_eq!(
+            stored.validators.validators_metadata,
+            validators.validators_metadata
+        );
+        let delegators = &stored.validators.validator_staking_pools["a.poolv1.near"];
+        assert_eq!(delegators.len(), 2);
+        assert_eq!(delegators["alice.near"].staked_balance, 15);
+        assert!(!stored
+            .validators
+            .validator_staking_pools
+            .contains_key("b.poolv1.near"));
+    }
+
+    #[tokio::test]
+    async fn lookups_are_answered_from_disk_without_loading_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delegators.sqlite");
+
+        let mut validators = ValidatorsWithTimestamp::default();
+        refreshed(
+            &mut validators,
+            "a.poolv1.near",
+            100,
+            &[("alice.near", 10), ("bob.near", 20)],
+        );
+        refreshed(&mut validators, "b.poolv1.near", 100, &[("alice.near", 30)]);
+        SqliteStore::open(&path)
+            .unwrap()
+            .flush(&validators, 2)
+            .await
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        let stakes = store.lookup("alice.near").await.unwrap().unwrap();
+        assert_eq!(
+            stakes,
+            BTreeMap::from([
+                (Id::from("a.poolv1.near"), stake(10)),
+                (Id::from("b.poolv1.near"), stake(30)),
+            ])
+        );
+        assert_eq!(
+            store.lookup("carol.near").await.unwrap(),
+            Some(BTreeMap::new())
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{AccountId, BlockHeight};
+use rocket::serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize};
+
+/// Pagoda Console alert: `{"payload": {"Actions": {"receipt_id", "block_hash"}}}`.
+#[derive(Debug, Deserialize)]
+struct PagodaWebhook {
+    payload: PagodaPayload,
+}
+
+#[derive(Debug, Deserialize)]
+struct PagodaPayload {
+    #[serde(rename = "Actions")]
+    actions: PagodaActions,
+}
+
+#[derive(Debug, Deserialize)]
+struct PagodaActions {
+    receipt_id: CryptoHash,
+    block_hash: CryptoHash,
+}
+
+/// `{"receiver_id", "block_height"}`, for indexers that already know both.
+#[derive(Debug, Deserialize)]
+struct SimpleUpdate {
+    receiver_id: AccountId,
+    block_height: BlockHeight,
+}
+
+/// Data stream event: `{"block_height", "receipts": [{"receiver_id", ...}]}`, with the
+/// receipts of a block that are of interest.
+#[derive(Debug, Deserialize)]
+struct StreamEvent {
+    block_height: BlockHeight,
+    receipts: Vec<StreamReceipt>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamReceipt {
+    receiver_id: AccountId,
+}
+
+/// A staking pool to refresh, as found in a webhook payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateRequest {
+    /// Both the staking pool and the block are known.
+    Resolved {
+        receiver_id: String,
+        block_height: BlockHeight,
+    },
+    /// The staking pool and the block have to be looked up with the RPC.
+    Receipt {
+        receipt_id: CryptoHash,
+        block_hash: CryptoHash,
+    },
+}
+
+/// Body of a rejected webhook call.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookError {
+    pub error: String,
+}
+
+impl WebhookError {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+        }
+    }
+}
+
+fn parse_as<T: DeserializeOwned>(value: &serde_json::Value, format: &str) -> Result<T, String> {
+    T::deserialize(value).map_err(|e| format!("Invalid {format} payload: {e}"))
+}
+
+/// Staking pools requested by a single (non-batch) payload.
+fn parse_single(value: &serde_json::Value) -> Result<Vec<UpdateRequest>, String> {
+    let Some(object) = value.as_object() else {
+        return Err("Expected a JSON object or an array of them".to_string());
+    };
+
+    if object.contains_key("payload") {
+        let webhook = parse_as::<PagodaWebhook>(value, "Pagoda")?;
+        Ok(vec![UpdateRequest::Receipt {
+            receipt_id: webhook.payload.actions.receipt_id,
+            block_hash: webhook.payload.actions.block_hash,
+        }])
+    } else if object.contains_key("receipts") {
+        let event = parse_as::<StreamEvent>(value, "data stream")?;
+        Ok(event
+            .receipts
+            .into_iter()
+            .map(|receipt| UpdateRequest::Resolved {
+                receiver_id: receipt.receiver_id.to_string(),
+                block_height: event.block_height,
+            })
+            .collect())
+    } else if object.contains_key("receiver_id") {
+        let update = parse_as::<SimpleUpdate>(value, "simple")?;
+        Ok(vec![UpdateRequest::Resolved {
+            receiver_id: update.receiver_id.to_string(),
+            block_height: update.block_height,
+        }])
+    } else {
+        Err(
+            "Unknown payload, expected a Pagoda alert (`payload`), a data stream event \
+             (`receipts`) or a simple update (`receiver_id`)"
+                .to_string(),
+        )
+    }
+}
+
+/// Staking pools requested by a webhook payload in any of the accepted formats, or a
+/// description of what is wrong with it.
+pub fn parse(value: &serde_json::Value) -> Result<Vec<UpdateRequest>, String> {
+    let requests = match value.as_array() {
+        Some(items) => items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| parse_single(item).map_err(|e| format!("Item {index}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        None => parse_single(value)?,
+    };
+
+    if requests.is_empty() {
+        return Err("Payload does not name any staking pool".to_string());
+    }
+
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const RECEIPT_ID: &str = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+    const BLOCK_HASH: &str = "8qbHbw2BbbTHBW1sbeqakYXVKRQM8Ne7pLK7m6CVfeR";
+
+    fn resolved(receiver_id: &str, block_height: BlockHeight) -> UpdateRequest {
+        UpdateRequest::Resolved {
+            receiver_id: receiver_id.to_string(),
+            block_height,
+        }
+    }
+
+    #[test]
+    fn pagoda_alerts_are_resolved_later() {
+        let requests = parse(&json!({
+            "secret": "ignored",
+            "payload": {
+                "Actions": {
+                    "block_hash": BLOCK_HASH,
+                    "receipt_id": RECEIPT_ID,
+                    "receiver_id": "a.poolv1.near",
+                },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            requests,
+            [UpdateRequest::Receipt {
+                receipt_id: RECEIPT_ID.parse().unwrap(),
+                block_hash: BLOCK_HASH.parse().unwrap(),
+            }]
+        );
+    }
+
+    #[test]
+    fn data_stream_events_name_every_receipt() {
+        let requests = parse(&json!({
+            "block_height": 101,
+            "receipts": [
+                { "receiver_id": "a.poolv1.near", "receipt_id": RECEIPT_ID },
+                { "receiver_id": "b.poolv1.near" },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            requests,
+            [
+                resolved("a.poolv1.near", 101),
+                resolved("b.poolv1.near", 101)
+            ]
+        );
+    }
+
+    #[test]
+    fn simple_updates_and_batches() {
+        assert_eq!(
+            parse(&json!({ "receiver_id": "a.poolv1.near", "block_height": 101 })).unwrap(),
+            [resolved("a.poolv1.near", 101)]
+        );
+
+        let requests = parse(&json!([
+            { "receiver_id": "a.poolv1.near", "block_height": 101 },
+            { "block_height": 102, "receipts": [{ "receiver_id": "b.poolv1.near" }] },
+        ]))
+        .unwrap();
+        assert_eq!(
+            requests,
+            [
+                resolved("a.poolv1.near", 101),
+                resolved("b.poolv1.near", 102)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_payloads_are_described() {
+        assert!(parse(&json!("a.poolv1.near"))
+            .unwrap_err()
+            .starts_with("Expected a JSON object"));
+        assert!(parse(&json!({ "account_id": "a.poolv1.near" }))
+            .unwrap_err()
+            .starts_with("Unknown payload"));
+        assert!(
+            parse(&json!({ "payload": { "Actions": { "receipt_id": "nope" } } }))
+                .unwrap_err()
+                .starts_with("Invalid Pagoda payload")
+        );
+        assert!(
+            parse(&json!({ "receiver_id": "Not An Account", "block_height": 101 }))
+                .unwrap_err()
+                .starts_with("Invalid simple payload")
+        );
+        assert!(parse(&json!([
+            { "receiver_id": "a.poolv1.near", "block_height": 101 },
+            { "receiver_id": "b.poolv1.near" },
+        ]))
+        .unwrap_err()
+        .starts_with("Item 1: Invalid simple payload"));
+        assert_eq!(
+            parse(&json!({ "block_height": 101, "receipts": [] })).unwrap_err(),
+            "Payload does not name any staking pool"
+        );
+        assert_eq!(
+            parse(&json!([])).unwrap_err(),
+            "Payload does not name any staking pool"
+        );
+    }
+}